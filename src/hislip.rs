@@ -0,0 +1,791 @@
+/*
+* HiSLIP, IVI-6.1 High-Speed LAN Instrument Protocol. The synchronous channel carries data and
+* triggers, the asynchronous channel device clear, locking, status queries and service requests.
+*/
+
+use crate::tcp::{connect, io_error, resolve};
+use crate::transport::Transport;
+use crate::usbtmc::{timeout_after, UsbtmcErrors};
+use async_io::Async;
+use futures_lite::future::block_on;
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use std::net::TcpStream;
+use std::time::Duration;
+
+pub const HISLIP_PORT: u16 = 4880;
+
+/*
+* IVI-6.1 Table 4 message types
+*/
+const INITIALIZE: u8 = 0;
+const INITIALIZE_RESPONSE: u8 = 1;
+const FATAL_ERROR: u8 = 2;
+const ERROR: u8 = 3;
+const ASYNC_LOCK: u8 = 4;
+const ASYNC_LOCK_RESPONSE: u8 = 5;
+const DATA: u8 = 6;
+const DATA_END: u8 = 7;
+const DEVICE_CLEAR_COMPLETE: u8 = 8;
+const DEVICE_CLEAR_ACKNOWLEDGE: u8 = 9;
+const ASYNC_REMOTE_LOCAL_CONTROL: u8 = 10;
+const ASYNC_REMOTE_LOCAL_RESPONSE: u8 = 11;
+const TRIGGER: u8 = 12;
+const INTERRUPTED: u8 = 13;
+const ASYNC_INTERRUPTED: u8 = 14;
+const ASYNC_MAXIMUM_MESSAGE_SIZE: u8 = 15;
+const ASYNC_MAXIMUM_MESSAGE_SIZE_RESPONSE: u8 = 16;
+const ASYNC_INITIALIZE: u8 = 17;
+const ASYNC_INITIALIZE_RESPONSE: u8 = 18;
+const ASYNC_DEVICE_CLEAR: u8 = 19;
+const ASYNC_SERVICE_REQUEST: u8 = 20;
+const ASYNC_STATUS_QUERY: u8 = 21;
+const ASYNC_STATUS_RESPONSE: u8 = 22;
+const ASYNC_DEVICE_CLEAR_ACKNOWLEDGE: u8 = 23;
+const ASYNC_LOCK_INFO: u8 = 24;
+const ASYNC_LOCK_INFO_RESPONSE: u8 = 25;
+
+/*
+* IVI-6.1 section 6.2 and 6.5 control codes
+*/
+const LOCK_RELEASE: u8 = 0;
+const LOCK_REQUEST: u8 = 1;
+const LOCK_FAILURE: u8 = 0;
+const LOCK_SUCCESS: u8 = 1;
+const LOCK_SUCCESS_SHARED: u8 = 2;
+
+const REMOTE_DISABLE: u8 = 0;
+const REMOTE_ENABLE: u8 = 1;
+const LOCAL_LOCKOUT: u8 = 4;
+const GO_TO_LOCAL: u8 = 6;
+
+const FEATURE_OVERLAPPED: u8 = 0x01;
+
+const HISLIP_PROTOCOL_VERSION: u16 = 0x0100;
+// vendor IDs are two ASCII characters of an instrument maker, zero does not claim one
+const HISLIP_VENDOR_ID: u16 = 0x0000;
+const HISLIP_INITIAL_MESSAGE_ID: u32 = 0xFFFF_FF00;
+const HISLIP_HEADER_SIZE: usize = 16;
+const HISLIP_DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+// largest payload kept in memory, Data and DataEnd payloads of any size are passed on in pieces
+const HISLIP_MAX_MESSAGE_SIZE: u64 = 1024 * 1024;
+const HISLIP_READ_SIZE: u64 = 64 * 1024;
+
+pub struct Hislip {
+    sync_channel: Async<TcpStream>,
+    async_channel: Async<TcpStream>,
+    session_id: u16,
+    // ID of the next message sent on the synchronous channel
+    message_id: u32,
+    // ID of the last message sent with write_message, its response carries the same ID
+    query_message_id: u32,
+    // set when a complete response arrived, reported with the next message
+    rmt_delivered: bool,
+    overlapped: bool,
+    max_message_size: u64,
+    pending_srq: Option<u8>,
+    timeout: Duration,
+    term_char: u8,
+}
+
+struct Header {
+    message_type: u8,
+    control_code: u8,
+    parameter: u32,
+    length: u64,
+}
+
+struct Message {
+    message_type: u8,
+    control_code: u8,
+    parameter: u32,
+    payload: Vec<u8>,
+}
+
+macro_rules! log {
+    // The `$(...)*` syntax is used to match against any number of arguments of any type
+    ($($arg:tt)*) => {
+        // Check if in debug mode and call `print!` if true
+        if cfg!(debug_assertions) {
+            print!($($arg)*);
+        }
+    };
+}
+
+/*
+* IVI-6.1 section 2.5 message format: "HS", message type, control code, message parameter and the
+* payload length, all big endian
+*/
+async fn send_message(
+    stream: &mut Async<TcpStream>,
+    timeout: Duration,
+    message_type: u8,
+    control_code: u8,
+    parameter: u32,
+    payload: &[u8],
+) -> Result<(), UsbtmcErrors> {
+    let mut message = Vec::with_capacity(HISLIP_HEADER_SIZE + payload.len());
+    message.extend_from_slice(b"HS");
+    message.push(message_type);
+    message.push(control_code);
+    message.extend_from_slice(&parameter.to_be_bytes());
+    message.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    message.extend_from_slice(payload);
+
+    timeout_after(stream.write_all(&message), timeout)
+        .await?
+        .map_err(io_error)
+}
+
+async fn receive_header(
+    stream: &mut Async<TcpStream>,
+    timeout: Duration,
+) -> Result<Header, UsbtmcErrors> {
+    let mut header = [0u8; HISLIP_HEADER_SIZE];
+    timeout_after(stream.read_exact(&mut header), timeout)
+        .await?
+        .map_err(io_error)?;
+
+    if &header[0..2] != b"HS" {
+        return Err(UsbtmcErrors::InvalidData);
+    }
+
+    let mut length = [0u8; 8];
+    length.copy_from_slice(&header[8..16]);
+
+    Ok(Header {
+        message_type: header[2],
+        control_code: header[3],
+        parameter: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+        length: u64::from_be_bytes(length),
+    })
+}
+
+/*
+* Reads `length` bytes of payload in pieces of at most HISLIP_READ_SIZE. Once `on_piece` fails the
+* rest is still read, so the stream stays at the start of the next message.
+*/
+async fn receive_payload<F>(
+    stream: &mut Async<TcpStream>,
+    timeout: Duration,
+    length: u64,
+    on_piece: &mut F,
+) -> Result<(), UsbtmcErrors>
+where
+    F: FnMut(&[u8]) -> Result<(), UsbtmcErrors>,
+{
+    let mut buffer = vec![0u8; length.min(HISLIP_READ_SIZE) as usize];
+    let mut remaining = length;
+    let mut result = Ok(());
+
+    while remaining > 0 {
+        let piece = &mut buffer[..remaining.min(HISLIP_READ_SIZE) as usize];
+        timeout_after(stream.read_exact(piece), timeout)
+            .await?
+            .map_err(io_error)?;
+        if result.is_ok() {
+            result = on_piece(piece);
+        }
+        remaining -= piece.len() as u64;
+    }
+
+    result
+}
+
+async fn receive_message(
+    stream: &mut Async<TcpStream>,
+    timeout: Duration,
+) -> Result<Message, UsbtmcErrors> {
+    let header = receive_header(stream, timeout).await?;
+    finish_message(stream, timeout, header).await
+}
+
+/*
+* Reads the payload after `header`. Only Data and DataEnd carry a lot of data, any other payload
+* larger than HISLIP_MAX_MESSAGE_SIZE is skipped and reported as invalid.
+*/
+async fn finish_message(
+    stream: &mut Async<TcpStream>,
+    timeout: Duration,
+    header: Header,
+) -> Result<Message, UsbtmcErrors> {
+    let mut payload = Vec::new();
+    let mut keep = |piece: &[u8]| {
+        if (payload.len() + piece.len()) as u64 > HISLIP_MAX_MESSAGE_SIZE {
+            return Err(UsbtmcErrors::InvalidData);
+        }
+        payload.extend_from_slice(piece);
+        Ok(())
+    };
+    receive_payload(stream, timeout, header.length, &mut keep).await?;
+
+    let message = Message {
+        message_type: header.message_type,
+        control_code: header.control_code,
+        parameter: header.parameter,
+        payload,
+    };
+
+    if message.message_type == ERROR || message.message_type == FATAL_ERROR {
+        log!(
+            "HiSLIP error {}: {}\n",
+            message.control_code,
+            String::from_utf8_lossy(&message.payload)
+        );
+        return Err(UsbtmcErrors::HislipError(message.control_code));
+    }
+
+    Ok(message)
+}
+
+fn expect_message(message: &Message, message_type: u8) -> Result<(), UsbtmcErrors> {
+    if message.message_type != message_type {
+        log!(
+            "Expected HiSLIP message {} but got {}\n",
+            message_type,
+            message.message_type
+        );
+        return Err(UsbtmcErrors::InvalidData);
+    }
+
+    Ok(())
+}
+
+/*
+* Opens a session with `sub_address`, such as "hislip0", on the instrument at `address`.
+* `address` is "host" or "host:port", the port defaults to 4880.
+*/
+pub fn open_hislip(address: &str, sub_address: &str) -> Result<Hislip, UsbtmcErrors> {
+    block_on(open_hislip_async(address, sub_address))
+}
+
+pub async fn open_hislip_async(address: &str, sub_address: &str) -> Result<Hislip, UsbtmcErrors> {
+    let socket_address = resolve(address, HISLIP_PORT)?
+        .into_iter()
+        .next()
+        .ok_or(UsbtmcErrors::DeviceNotFound)?;
+
+    // IVI-6.1 section 6.1 initialization transaction
+    let mut sync_channel = connect(socket_address, HISLIP_DEFAULT_TIMEOUT).await?;
+    send_message(
+        &mut sync_channel,
+        HISLIP_DEFAULT_TIMEOUT,
+        INITIALIZE,
+        0,
+        ((HISLIP_PROTOCOL_VERSION as u32) << 16) | HISLIP_VENDOR_ID as u32,
+        sub_address.as_bytes(),
+    )
+    .await?;
+    let response = receive_message(&mut sync_channel, HISLIP_DEFAULT_TIMEOUT).await?;
+    expect_message(&response, INITIALIZE_RESPONSE)?;
+
+    let overlapped = response.control_code & FEATURE_OVERLAPPED != 0;
+    let session_id = response.parameter as u16;
+    log!(
+        "HiSLIP session {}, server version {:#06x}, overlapped {}\n",
+        session_id,
+        response.parameter >> 16,
+        overlapped
+    );
+
+    let mut async_channel = connect(socket_address, HISLIP_DEFAULT_TIMEOUT).await?;
+    send_message(
+        &mut async_channel,
+        HISLIP_DEFAULT_TIMEOUT,
+        ASYNC_INITIALIZE,
+        0,
+        session_id as u32,
+        &[],
+    )
+    .await?;
+    let response = receive_message(&mut async_channel, HISLIP_DEFAULT_TIMEOUT).await?;
+    expect_message(&response, ASYNC_INITIALIZE_RESPONSE)?;
+
+    let mut hislip = Hislip {
+        sync_channel,
+        async_channel,
+        session_id,
+        message_id: HISLIP_INITIAL_MESSAGE_ID,
+        query_message_id: HISLIP_INITIAL_MESSAGE_ID.wrapping_sub(2),
+        rmt_delivered: false,
+        overlapped,
+        max_message_size: u64::MAX,
+        pending_srq: None,
+        timeout: HISLIP_DEFAULT_TIMEOUT,
+        term_char: b'\n',
+    };
+
+    // IVI-6.1 section 6.3, the server reports the largest message it accepts
+    let response = async_transaction(
+        &mut hislip,
+        ASYNC_MAXIMUM_MESSAGE_SIZE,
+        0,
+        0,
+        &HISLIP_MAX_MESSAGE_SIZE.to_be_bytes(),
+        ASYNC_MAXIMUM_MESSAGE_SIZE_RESPONSE,
+    )
+    .await?;
+    if response.payload.len() == 8 {
+        let mut size = [0u8; 8];
+        size.copy_from_slice(&response.payload);
+        hislip.max_message_size = u64::from_be_bytes(size).max(1);
+    }
+    log!("Server maximum message size: {}\n", hislip.max_message_size);
+
+    Ok(hislip)
+}
+
+/*
+* Sends a request on the asynchronous channel and waits for its response. Service requests that
+* arrive in between are kept for hislip_wait_for_srq.
+*/
+async fn async_transaction(
+    hislip: &mut Hislip,
+    message_type: u8,
+    control_code: u8,
+    parameter: u32,
+    payload: &[u8],
+    response_type: u8,
+) -> Result<Message, UsbtmcErrors> {
+    let timeout = hislip.timeout;
+    async_transaction_within(
+        hislip,
+        timeout,
+        message_type,
+        control_code,
+        parameter,
+        payload,
+        response_type,
+    )
+    .await
+}
+
+async fn async_transaction_within(
+    hislip: &mut Hislip,
+    timeout: Duration,
+    message_type: u8,
+    control_code: u8,
+    parameter: u32,
+    payload: &[u8],
+    response_type: u8,
+) -> Result<Message, UsbtmcErrors> {
+    send_message(
+        &mut hislip.async_channel,
+        timeout,
+        message_type,
+        control_code,
+        parameter,
+        payload,
+    )
+    .await?;
+
+    loop {
+        let message = receive_message(&mut hislip.async_channel, timeout).await?;
+
+        match message.message_type {
+            ASYNC_SERVICE_REQUEST => hislip.pending_srq = Some(message.control_code),
+            ASYNC_INTERRUPTED => log!("Response {} interrupted\n", message.parameter),
+            _ => {
+                expect_message(&message, response_type)?;
+                return Ok(message);
+            }
+        }
+    }
+}
+
+fn take_rmt_delivered(hislip: &mut Hislip) -> u8 {
+    std::mem::take(&mut hislip.rmt_delivered) as u8
+}
+
+// ID of the last complete message sent on the synchronous channel
+fn last_message_id(hislip: &Hislip) -> u32 {
+    hislip.message_id.wrapping_sub(2)
+}
+
+pub fn hislip_session_id(hislip: &Hislip) -> u16 {
+    hislip.session_id
+}
+
+/*
+* True if the server runs in overlapped mode, false in synchronized mode
+*/
+pub fn hislip_is_overlapped(hislip: &Hislip) -> bool {
+    hislip.overlapped
+}
+
+/*
+* Sets the character that terminates responses. Defaults to '\n'
+*/
+pub fn set_hislip_term_char(hislip: &mut Hislip, term_char: u8) {
+    hislip.term_char = term_char;
+}
+
+/*
+* IVI-6.1 section 4.12 Trigger, the same as a GPIB group execute trigger
+*/
+pub fn hislip_trigger(hislip: &mut Hislip) -> Result<(), UsbtmcErrors> {
+    block_on(hislip_trigger_async(hislip))
+}
+
+pub async fn hislip_trigger_async(hislip: &mut Hislip) -> Result<(), UsbtmcErrors> {
+    let rmt_delivered = take_rmt_delivered(hislip);
+    send_message(
+        &mut hislip.sync_channel,
+        hislip.timeout,
+        TRIGGER,
+        rmt_delivered,
+        hislip.message_id,
+        &[],
+    )
+    .await?;
+    hislip.message_id = hislip.message_id.wrapping_add(2);
+
+    Ok(())
+}
+
+/*
+* IVI-6.1 section 6.12 device clear
+*/
+pub fn hislip_clear(hislip: &mut Hislip) -> Result<(), UsbtmcErrors> {
+    block_on(hislip_clear_async(hislip))
+}
+
+pub async fn hislip_clear_async(hislip: &mut Hislip) -> Result<(), UsbtmcErrors> {
+    let overlapped = hislip.overlapped;
+    hislip_clear_with_mode_async(hislip, overlapped).await
+}
+
+/*
+* Device clear that also asks the server for overlapped or synchronized mode. The mode can only
+* change during a device clear, hislip_is_overlapped tells what the server agreed to.
+*/
+pub fn hislip_clear_with_mode(hislip: &mut Hislip, overlapped: bool) -> Result<(), UsbtmcErrors> {
+    block_on(hislip_clear_with_mode_async(hislip, overlapped))
+}
+
+pub async fn hislip_clear_with_mode_async(
+    hislip: &mut Hislip,
+    overlapped: bool,
+) -> Result<(), UsbtmcErrors> {
+    async_transaction(
+        hislip,
+        ASYNC_DEVICE_CLEAR,
+        0,
+        0,
+        &[],
+        ASYNC_DEVICE_CLEAR_ACKNOWLEDGE,
+    )
+    .await?;
+
+    let feature = if overlapped { FEATURE_OVERLAPPED } else { 0 };
+    send_message(
+        &mut hislip.sync_channel,
+        hislip.timeout,
+        DEVICE_CLEAR_COMPLETE,
+        feature,
+        0,
+        &[],
+    )
+    .await?;
+
+    // whatever was still queued on the synchronous channel is discarded
+    loop {
+        let header = receive_header(&mut hislip.sync_channel, hislip.timeout).await?;
+
+        if header.message_type == DATA || header.message_type == DATA_END {
+            log!("Discarding {} bytes of data\n", header.length);
+            receive_payload(
+                &mut hislip.sync_channel,
+                hislip.timeout,
+                header.length,
+                &mut |_| Ok(()),
+            )
+            .await?;
+            continue;
+        }
+
+        let message = finish_message(&mut hislip.sync_channel, hislip.timeout, header).await?;
+        if message.message_type == DEVICE_CLEAR_ACKNOWLEDGE {
+            hislip.overlapped = message.control_code & FEATURE_OVERLAPPED != 0;
+            break;
+        }
+        log!("Discarding HiSLIP message {}\n", message.message_type);
+    }
+
+    hislip.message_id = HISLIP_INITIAL_MESSAGE_ID;
+    hislip.query_message_id = HISLIP_INITIAL_MESSAGE_ID.wrapping_sub(2);
+    hislip.rmt_delivered = false;
+
+    Ok(())
+}
+
+/*
+* IVI-6.1 section 6.7 status query, the status byte without going through the message queue
+*/
+pub fn hislip_read_status_byte(hislip: &mut Hislip) -> Result<u8, UsbtmcErrors> {
+    block_on(hislip_read_status_byte_async(hislip))
+}
+
+pub async fn hislip_read_status_byte_async(hislip: &mut Hislip) -> Result<u8, UsbtmcErrors> {
+    let rmt_delivered = take_rmt_delivered(hislip);
+    let parameter = last_message_id(hislip);
+    let response = async_transaction(
+        hislip,
+        ASYNC_STATUS_QUERY,
+        rmt_delivered,
+        parameter,
+        &[],
+        ASYNC_STATUS_RESPONSE,
+    )
+    .await?;
+
+    Ok(response.control_code)
+}
+
+/*
+* IVI-6.1 section 6.9 AsyncServiceRequest. Waits up to the timeout for the instrument to request
+* service and returns the status byte.
+*/
+pub fn hislip_wait_for_srq(hislip: &mut Hislip) -> Result<u8, UsbtmcErrors> {
+    block_on(hislip_wait_for_srq_async(hislip))
+}
+
+pub async fn hislip_wait_for_srq_async(hislip: &mut Hislip) -> Result<u8, UsbtmcErrors> {
+    if let Some(stb) = hislip.pending_srq.take() {
+        return Ok(stb);
+    }
+
+    loop {
+        let message = receive_message(&mut hislip.async_channel, hislip.timeout).await?;
+        if message.message_type == ASYNC_SERVICE_REQUEST {
+            return Ok(message.control_code);
+        }
+        log!("Ignoring HiSLIP message {}\n", message.message_type);
+    }
+}
+
+/*
+* IVI-6.1 section 6.5 lock. An empty `lock_string` requests the exclusive lock, any other string a
+* shared lock with that name. Waits up to `wait` for other clients to release it.
+*/
+pub fn hislip_lock(
+    hislip: &mut Hislip,
+    lock_string: &str,
+    wait: Duration,
+) -> Result<(), UsbtmcErrors> {
+    block_on(hislip_lock_async(hislip, lock_string, wait))
+}
+
+pub async fn hislip_lock_async(
+    hislip: &mut Hislip,
+    lock_string: &str,
+    wait: Duration,
+) -> Result<(), UsbtmcErrors> {
+    // the response may take as long as the server waits for the lock
+    let timeout = hislip.timeout + wait;
+    let response = async_transaction_within(
+        hislip,
+        timeout,
+        ASYNC_LOCK,
+        LOCK_REQUEST,
+        wait.as_millis().min(u32::MAX as u128) as u32,
+        lock_string.as_bytes(),
+        ASYNC_LOCK_RESPONSE,
+    )
+    .await?;
+
+    match response.control_code {
+        LOCK_SUCCESS => Ok(()),
+        LOCK_FAILURE => Err(UsbtmcErrors::Timeout),
+        _ => Err(UsbtmcErrors::LockFailed),
+    }
+}
+
+pub fn hislip_unlock(hislip: &mut Hislip) -> Result<(), UsbtmcErrors> {
+    block_on(hislip_unlock_async(hislip))
+}
+
+pub async fn hislip_unlock_async(hislip: &mut Hislip) -> Result<(), UsbtmcErrors> {
+    let parameter = last_message_id(hislip);
+    let response = async_transaction(
+        hislip,
+        ASYNC_LOCK,
+        LOCK_RELEASE,
+        parameter,
+        &[],
+        ASYNC_LOCK_RESPONSE,
+    )
+    .await?;
+
+    match response.control_code {
+        LOCK_SUCCESS | LOCK_SUCCESS_SHARED => Ok(()),
+        _ => Err(UsbtmcErrors::LockFailed),
+    }
+}
+
+/*
+* IVI-6.1 section 6.6 lock info. Returns whether an exclusive lock is granted and how many clients
+* hold a lock.
+*/
+pub fn hislip_lock_info(hislip: &mut Hislip) -> Result<(bool, u32), UsbtmcErrors> {
+    block_on(hislip_lock_info_async(hislip))
+}
+
+pub async fn hislip_lock_info_async(hislip: &mut Hislip) -> Result<(bool, u32), UsbtmcErrors> {
+    let response =
+        async_transaction(hislip, ASYNC_LOCK_INFO, 0, 0, &[], ASYNC_LOCK_INFO_RESPONSE).await?;
+
+    Ok((response.control_code != 0, response.parameter))
+}
+
+/*
+* IVI-6.1 section 6.4 remote/local control
+*/
+async fn remote_local_request(hislip: &mut Hislip, request: u8) -> Result<(), UsbtmcErrors> {
+    let parameter = last_message_id(hislip);
+    async_transaction(
+        hislip,
+        ASYNC_REMOTE_LOCAL_CONTROL,
+        request,
+        parameter,
+        &[],
+        ASYNC_REMOTE_LOCAL_RESPONSE,
+    )
+    .await
+    .map(|_| ())
+}
+
+pub fn hislip_ren_control(hislip: &mut Hislip, enable: bool) -> Result<(), UsbtmcErrors> {
+    block_on(hislip_ren_control_async(hislip, enable))
+}
+
+pub async fn hislip_ren_control_async(
+    hislip: &mut Hislip,
+    enable: bool,
+) -> Result<(), UsbtmcErrors> {
+    let request = if enable {
+        REMOTE_ENABLE
+    } else {
+        REMOTE_DISABLE
+    };
+    remote_local_request(hislip, request).await
+}
+
+pub fn hislip_go_to_local(hislip: &mut Hislip) -> Result<(), UsbtmcErrors> {
+    block_on(hislip_go_to_local_async(hislip))
+}
+
+pub async fn hislip_go_to_local_async(hislip: &mut Hislip) -> Result<(), UsbtmcErrors> {
+    remote_local_request(hislip, GO_TO_LOCAL).await
+}
+
+pub fn hislip_local_lockout(hislip: &mut Hislip) -> Result<(), UsbtmcErrors> {
+    block_on(hislip_local_lockout_async(hislip))
+}
+
+pub async fn hislip_local_lockout_async(hislip: &mut Hislip) -> Result<(), UsbtmcErrors> {
+    remote_local_request(hislip, LOCAL_LOCKOUT).await
+}
+
+impl Transport for Hislip {
+    /*
+     * IVI-6.1 section 4.2 Data and DataEnd. Every part of the message carries the same message ID.
+     */
+    async fn write_message(&mut self, data: &[u8]) -> Result<(), UsbtmcErrors> {
+        let max_size = self.max_message_size.min(usize::MAX as u64) as usize;
+        let mut chunks = data.chunks(max_size).peekable();
+
+        loop {
+            let chunk = chunks.next().unwrap_or(&[]);
+            let last = chunks.peek().is_none();
+            let message_type = if last { DATA_END } else { DATA };
+
+            let rmt_delivered = take_rmt_delivered(self);
+            send_message(
+                &mut self.sync_channel,
+                self.timeout,
+                message_type,
+                rmt_delivered,
+                self.message_id,
+                chunk,
+            )
+            .await?;
+
+            if last {
+                self.query_message_id = self.message_id;
+                self.message_id = self.message_id.wrapping_add(2);
+                return Ok(());
+            }
+        }
+    }
+
+    /*
+     * IVI-6.1 section 4.3 and 4.4. Data and DataEnd of an older message, such as the rest of an
+     * interrupted response, are read and dropped.
+     */
+    async fn read_message<F>(&mut self, on_payload: &mut F) -> Result<(), UsbtmcErrors>
+    where
+        F: FnMut(&[u8]) -> Result<(), UsbtmcErrors> + Send,
+    {
+        loop {
+            let header = receive_header(&mut self.sync_channel, self.timeout).await?;
+            log!(
+                "HiSLIP message {}, id {:#010x}, {} bytes\n",
+                header.message_type,
+                header.parameter,
+                header.length
+            );
+
+            match header.message_type {
+                DATA | DATA_END if header.parameter != self.query_message_id => {
+                    log!("Dropping response to message {}\n", header.parameter);
+                    receive_payload(
+                        &mut self.sync_channel,
+                        self.timeout,
+                        header.length,
+                        &mut |_| Ok(()),
+                    )
+                    .await?;
+                }
+                DATA | DATA_END => {
+                    receive_payload(
+                        &mut self.sync_channel,
+                        self.timeout,
+                        header.length,
+                        on_payload,
+                    )
+                    .await?;
+                    if header.message_type == DATA_END {
+                        self.rmt_delivered = true;
+                        return Ok(());
+                    }
+                }
+                _ => {
+                    let message =
+                        finish_message(&mut self.sync_channel, self.timeout, header).await?;
+                    match message.message_type {
+                        // synchronized mode, the response to an earlier message was dropped by the
+                        // server
+                        INTERRUPTED => log!("Response {} interrupted\n", message.parameter),
+                        _ => return Err(UsbtmcErrors::InvalidData),
+                    }
+                }
+            }
+        }
+    }
+
+    async fn clear(&mut self) -> Result<(), UsbtmcErrors> {
+        hislip_clear_async(self).await
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn term_char(&self) -> u8 {
+        self.term_char
+    }
+}
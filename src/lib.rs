@@ -1,19 +1,51 @@
+pub mod hislip;
+pub mod serial;
+pub mod tcp;
+pub mod transport;
 pub mod usbtmc;
+pub mod vxi11;
+use crate::transport::Transport;
 use crate::usbtmc::*;
 use usbtmc::UsbtmcErrors;
 
+use futures_lite::future::block_on;
+use futures_timer::Delay;
 use nusb::descriptors::InterfaceAltSetting;
 use nusb::transfer::Direction;
 use nusb::transfer::EndpointType;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
 
 pub struct Usbtmc {
     pub device: nusb::Device,
     pub interface: nusb::Interface,
+    interface_number: u8,
+    pub protocol: Protocol,
     endpoint_in_addr: u8,
     endpoint_out_addr: u8,
     endpoint_in_max_packet_size: usize,
     #[allow(dead_code)]
     endpoint_out_max_packet_size: usize,
+    endpoint_interrupt_in_addr: Option<u8>,
+    endpoint_interrupt_in_max_packet_size: usize,
+    speed: Option<nusb::Speed>,
+    btag: u8,
+    last_btag_out: u8,
+    last_btag_in: u8,
+    status_btag: u8,
+    pending_srq: Option<u8>,
+    srq_listener: Option<SrqListener>,
+    term_char: u8,
+    term_char_enabled: bool,
+    timeout: Duration,
+    pub capabilities: Capabilities,
+    vendor_id: u16,
+    product_id: u16,
+    serial_number: Option<String>,
+    disconnected: AtomicBool,
+    reconnect_policy: Option<ReconnectPolicy>,
+    event_handler: Option<EventHandler>,
 }
 
 macro_rules! log {
@@ -26,53 +58,393 @@ macro_rules! log {
     };
 }
 
-pub fn query(usbtmc: &mut Usbtmc, command: &str) -> Result<String, UsbtmcErrors> {
-    send_command(usbtmc, command)
+pub fn query<T: Transport>(transport: &mut T, command: &str) -> Result<String, UsbtmcErrors> {
+    block_on(query_async(transport, command))
 }
 
-pub fn query_raw(usbtmc: &mut Usbtmc, command: &str) -> Result<Vec<u8>, UsbtmcErrors> {
-    send_command_raw(usbtmc, command)
+pub async fn query_async<T: Transport>(
+    transport: &mut T,
+    command: &str,
+) -> Result<String, UsbtmcErrors> {
+    let data: Vec<u8> = query_raw_async(transport, command).await?;
+
+    let ascii_string: String = data.iter().map(|&b| b as char).collect();
+
+    Ok(ascii_string)
+}
+
+pub fn query_raw<T: Transport>(transport: &mut T, command: &str) -> Result<Vec<u8>, UsbtmcErrors> {
+    block_on(query_raw_async(transport, command))
+}
+
+/*
+* Sends `command` with a newline and, if it is a query, returns the response without the
+* termination character
+*/
+pub async fn query_raw_async<T: Transport>(
+    transport: &mut T,
+    command: &str,
+) -> Result<Vec<u8>, UsbtmcErrors> {
+    let command_with_newline = command.to_owned() + "\n";
+    let command_data = command_with_newline.as_bytes();
+
+    log!("Sending command: {:?}\n", command);
+    transport.write_message(command_data).await?;
+
+    let mut response: Vec<u8> = Vec::new();
+    if !is_query(command_data) {
+        return Ok(response);
+    }
+
+    transport
+        .read_message(&mut |payload: &[u8]| {
+            response.extend_from_slice(payload);
+            Ok(())
+        })
+        .await?;
+    log!("total response size: {}\n", response.len());
+
+    if response.last() != Some(&transport.term_char()) {
+        return Err(UsbtmcErrors::InvalidData);
+    }
+    response.truncate(response.len() - 1);
+
+    Ok(response)
 }
 
-pub fn write(usbtmc: &mut Usbtmc, command: &str) -> Result<(), UsbtmcErrors> {
-    let _ = send_command_raw(usbtmc, command)?;
+pub fn write<T: Transport>(transport: &mut T, command: &str) -> Result<(), UsbtmcErrors> {
+    block_on(write_async(transport, command))
+}
+
+pub async fn write_async<T: Transport>(
+    transport: &mut T,
+    command: &str,
+) -> Result<(), UsbtmcErrors> {
+    let _ = query_raw_async(transport, command).await?;
 
     Ok(())
 }
 
+/*
+* A connected device with a USBTMC interface, as reported by list_instruments
+*/
+#[derive(Debug, Clone)]
+pub struct InstrumentInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub bus_number: u8,
+    pub device_address: u8,
+    pub port_path: Option<String>,
+    pub interface_number: u8,
+    pub protocol: Protocol,
+    pub resource: String,
+}
+
+/*
+* Lists every connected device that has a USBTMC interface. Devices without a USBTMC interface are
+* skipped.
+*/
+pub fn list_instruments() -> Result<Vec<InstrumentInfo>, UsbtmcErrors> {
+    let devices = nusb::list_devices().map_err(UsbtmcErrors::Io)?;
+
+    let instruments = devices
+        .filter_map(|device_info| {
+            let interface = device_info
+                .interfaces()
+                .find(|interface| is_usbtmc_interface(interface.class(), interface.subclass()))?;
+
+            let serial_number = device_info.serial_number().map(str::to_string);
+            let resource = resource_string(
+                device_info.vendor_id(),
+                device_info.product_id(),
+                serial_number.as_deref(),
+                interface.interface_number(),
+            );
+
+            Some(InstrumentInfo {
+                vendor_id: device_info.vendor_id(),
+                product_id: device_info.product_id(),
+                serial_number,
+                manufacturer: device_info.manufacturer_string().map(str::to_string),
+                product: device_info.product_string().map(str::to_string),
+                bus_number: device_info.bus_number(),
+                device_address: device_info.device_address(),
+                port_path: port_path(&device_info),
+                interface_number: interface.interface_number(),
+                protocol: Protocol::from_interface_protocol(interface.protocol()),
+                resource,
+            })
+        })
+        .collect();
+
+    Ok(instruments)
+}
+
+/*
+* VISA resource string, USB[board]::manufacturer ID::model code::serial number[::interface]::INSTR.
+* The interface number is left out for interface 0 and when the device has no serial number.
+*/
+fn resource_string(
+    vendor_id: u16,
+    product_id: u16,
+    serial_number: Option<&str>,
+    interface_number: u8,
+) -> String {
+    match serial_number {
+        Some(serial) if interface_number != 0 => format!(
+            "USB0::0x{:04X}::0x{:04X}::{}::{}::INSTR",
+            vendor_id, product_id, serial, interface_number
+        ),
+        Some(serial) => format!(
+            "USB0::0x{:04X}::0x{:04X}::{}::INSTR",
+            vendor_id, product_id, serial
+        ),
+        None => format!("USB0::0x{:04X}::0x{:04X}::INSTR", vendor_id, product_id),
+    }
+}
+
+/*
+* Opens the device matching a "VID:PID" string such as "2A8D:8d01", both in hex. Fails with
+* MultipleDevices if more than one is connected, use open_device_with to pick one of them.
+*/
 pub fn open_device(vid_pid: &str) -> Result<Usbtmc, UsbtmcErrors> {
-    let vid = u16::from_str_radix(&vid_pid[0..4], 16).unwrap();
-    let pid = u16::from_str_radix(&vid_pid[5..9], 16).unwrap();
+    let (vid, pid) = parse_vid_pid(vid_pid)?;
+
+    block_on(open_usb(vid, pid, None, None))
+}
 
-    let device_info: nusb::DeviceInfo = nusb::list_devices()
-        .unwrap()
-        .find(|dev| dev.vendor_id() == vid && dev.product_id() == pid)
-        .expect("device not connected");
+/*
+* Opens the device matching a "VID:PID" string that is picked by `selector`
+*/
+pub fn open_device_with(vid_pid: &str, selector: DeviceSelector) -> Result<Usbtmc, UsbtmcErrors> {
+    let (vid, pid) = parse_vid_pid(vid_pid)?;
 
-    let device: nusb::Device = device_info.open().expect("failed to open device");
-    let interface: nusb::Interface = device
-        .detach_and_claim_interface(0)
-        .expect("failed to claim interface");
+    block_on(open_usb(vid, pid, Some(&selector), None))
+}
+
+fn parse_vid_pid(vid_pid: &str) -> Result<(u16, u16), UsbtmcErrors> {
+    let invalid = || UsbtmcErrors::InvalidResource(vid_pid.to_string());
+
+    let (vid, pid) = vid_pid.split_once(':').ok_or_else(invalid)?;
+    let vid = u16::from_str_radix(vid.trim(), 16).map_err(|_| invalid())?;
+    let pid = u16::from_str_radix(pid.trim(), 16).map_err(|_| invalid())?;
+
+    Ok((vid, pid))
+}
+
+/*
+* Picks one of several connected devices with the same VID and PID
+*/
+pub enum DeviceSelector {
+    Serial(String),
+    // bus and port chain such as "1-2.3", see port_path
+    PortPath(String),
+    Predicate(Box<dyn Fn(&nusb::DeviceInfo) -> bool + Send + Sync>),
+}
+
+impl DeviceSelector {
+    fn matches(&self, device_info: &nusb::DeviceInfo) -> bool {
+        match self {
+            DeviceSelector::Serial(serial) => device_info.serial_number() == Some(serial.as_str()),
+            DeviceSelector::PortPath(path) => port_path(device_info).as_deref() == Some(path),
+            DeviceSelector::Predicate(predicate) => predicate(device_info),
+        }
+    }
+}
+
+/*
+* Bus number and chain of hub ports leading to the device, such as "1-2.3". It stays the same
+* while the cabling is unchanged, unlike the device address. Not available on Windows.
+*/
+pub fn port_path(device_info: &nusb::DeviceInfo) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        device_info
+            .sysfs_path()
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(str::to_string)
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        // the location ID holds the bus in the top byte and one port per nibble below it
+        let location_id = device_info.location_id();
+        let ports: Vec<String> = (0..6)
+            .rev()
+            .map(|nibble| (location_id >> (nibble * 4)) & 0xF)
+            .take_while(|port| *port != 0)
+            .map(|port| port.to_string())
+            .collect();
+
+        Some(format!("{}-{}", location_id >> 24, ports.join(".")))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        let _ = device_info;
+        None
+    }
+}
+
+/*
+* A parsed VISA USB resource string, see parse_resource
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbResource {
+    pub board: u16,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial_number: Option<String>,
+    pub interface_number: Option<u8>,
+}
+
+/*
+* Parses a VISA resource string such as "USB0::0x2A8D::0x8D01::MY12345678::0::INSTR". The IDs can be
+* hex with a 0x prefix or decimal, the serial number, interface number and ::INSTR suffix are all
+* optional.
+*/
+pub fn parse_resource(resource: &str) -> Result<UsbResource, UsbtmcErrors> {
+    let invalid = || UsbtmcErrors::InvalidResource(resource.to_string());
+
+    let mut fields: Vec<&str> = resource.trim().split("::").collect();
+    if fields
+        .last()
+        .is_some_and(|field| field.eq_ignore_ascii_case("INSTR"))
+    {
+        fields.pop();
+    }
+
+    if fields.len() < 3 || fields.len() > 5 {
+        return Err(invalid());
+    }
+
+    let interface_type = fields[0].to_ascii_uppercase();
+    let board = interface_type.strip_prefix("USB").ok_or_else(invalid)?;
+    let board = if board.is_empty() {
+        0
+    } else {
+        board.parse::<u16>().map_err(|_| invalid())?
+    };
+
+    let vendor_id = parse_resource_id(fields[1]).ok_or_else(invalid)?;
+    let product_id = parse_resource_id(fields[2]).ok_or_else(invalid)?;
+
+    let serial_number = fields.get(3).map(|serial| serial.to_string());
+    let interface_number = match fields.get(4) {
+        Some(number) => Some(number.parse::<u8>().map_err(|_| invalid())?),
+        None => None,
+    };
+
+    Ok(UsbResource {
+        board,
+        vendor_id,
+        product_id,
+        serial_number,
+        interface_number,
+    })
+}
+
+fn parse_resource_id(id: &str) -> Option<u16> {
+    match id.strip_prefix("0x").or_else(|| id.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => id.parse::<u16>().ok(),
+    }
+}
+
+/*
+* Opens the instrument named by a VISA resource string, see parse_resource
+*/
+pub fn open_resource(resource: &str) -> Result<Usbtmc, UsbtmcErrors> {
+    let resource = parse_resource(resource)?;
+
+    let selector = resource.serial_number.map(DeviceSelector::Serial);
+
+    block_on(open_usb(
+        resource.vendor_id,
+        resource.product_id,
+        selector.as_ref(),
+        resource.interface_number,
+    ))
+}
+
+async fn open_usb(
+    vid: u16,
+    pid: u16,
+    selector: Option<&DeviceSelector>,
+    interface_number: Option<u8>,
+) -> Result<Usbtmc, UsbtmcErrors> {
+    let mut matching: Vec<nusb::DeviceInfo> = nusb::list_devices()
+        .map_err(UsbtmcErrors::Io)?
+        .filter(|dev| {
+            dev.vendor_id() == vid
+                && dev.product_id() == pid
+                && selector.is_none_or(|selector| selector.matches(dev))
+        })
+        .collect();
+
+    let device_info: nusb::DeviceInfo = match matching.len() {
+        0 => return Err(UsbtmcErrors::DeviceNotFound),
+        1 => matching.remove(0),
+        count => return Err(UsbtmcErrors::MultipleDevices(count)),
+    };
+
+    open_device_info(&device_info, interface_number).await
+}
+
+async fn open_device_info(
+    device_info: &nusb::DeviceInfo,
+    interface_number: Option<u8>,
+) -> Result<Usbtmc, UsbtmcErrors> {
+    let device: nusb::Device = device_info.open().map_err(UsbtmcErrors::Io)?;
 
     let config: nusb::descriptors::Configuration<'_> = device
         .active_configuration()
-        .expect("failed to get active configuration");
+        .map_err(|e| UsbtmcErrors::Io(std::io::Error::other(e)))?;
+
+    // composite instruments can put USBTMC next to other functions, so look it up by class
+    let alt_setting: InterfaceAltSetting = config
+        .interface_alt_settings()
+        .find(|alt| {
+            is_usbtmc_interface(alt.class(), alt.subclass())
+                && interface_number.is_none_or(|number| alt.interface_number() == number)
+        })
+        .ok_or(UsbtmcErrors::NoUsbtmcInterface)?;
+
+    let interface_number = alt_setting.interface_number();
+    let protocol = Protocol::from_interface_protocol(alt_setting.protocol());
+    log!(
+        "USBTMC interface {} alt setting {} protocol {:?}\n",
+        interface_number,
+        alt_setting.alternate_setting(),
+        protocol
+    );
+
+    let interface: nusb::Interface = device
+        .detach_and_claim_interface(interface_number)
+        .map_err(UsbtmcErrors::Io)?;
 
-    let inetrface_alt_settings: Vec<InterfaceAltSetting> =
-        config.interface_alt_settings().collect();
+    if alt_setting.alternate_setting() != 0 {
+        interface
+            .set_alt_setting(alt_setting.alternate_setting())
+            .map_err(UsbtmcErrors::Io)?;
+    }
 
-    let endpoint_in = inetrface_alt_settings[0]
+    let endpoint_in = alt_setting
         .endpoints()
-        .find(|ep| (ep.direction() == Direction::In && ep.transfer_type() == EndpointType::Bulk))
-        .expect("failed to find endpoint_in");
+        .find(|ep| ep.direction() == Direction::In && ep.transfer_type() == EndpointType::Bulk)
+        .ok_or(UsbtmcErrors::EndpointNotFound)?;
 
     let address_in = endpoint_in.address();
     log!("Endpoint in Address is: 0x{:x}\n", address_in);
 
-    let endpoint_out = inetrface_alt_settings[0]
+    let endpoint_out = alt_setting
         .endpoints()
         .find(|ep| ep.direction() == Direction::Out && ep.transfer_type() == EndpointType::Bulk)
-        .expect("failed to find endpoint_out");
+        .ok_or(UsbtmcErrors::EndpointNotFound)?;
 
     let address_out = endpoint_out.address();
     log!("Endpoint out Address is: 0x{:x}\n", address_out);
@@ -80,44 +452,327 @@ pub fn open_device(vid_pid: &str) -> Result<Usbtmc, UsbtmcErrors> {
     let endpoint_in_max_packet_size = endpoint_in.max_packet_size();
     let endpoint_out_max_packet_size = endpoint_out.max_packet_size();
 
-    Ok(Usbtmc {
+    let endpoint_interrupt_in = alt_setting.endpoints().find(|ep| {
+        ep.direction() == Direction::In && ep.transfer_type() == EndpointType::Interrupt
+    });
+
+    let address_interrupt_in = endpoint_interrupt_in.as_ref().map(|ep| ep.address());
+    log!(
+        "Endpoint interrupt in Address is: {:x?}\n",
+        address_interrupt_in
+    );
+
+    let endpoint_interrupt_in_max_packet_size = endpoint_interrupt_in
+        .as_ref()
+        .map_or(0, |ep| ep.max_packet_size());
+
+    let mut usbtmc = Usbtmc {
         device,
         interface,
+        interface_number,
+        protocol,
         endpoint_in_addr: address_in,
         endpoint_out_addr: address_out,
         endpoint_in_max_packet_size,
         endpoint_out_max_packet_size,
-    })
+        endpoint_interrupt_in_addr: address_interrupt_in,
+        endpoint_interrupt_in_max_packet_size,
+        speed: device_info.speed(),
+        btag: 0,
+        last_btag_out: 0,
+        last_btag_in: 0,
+        status_btag: 1,
+        pending_srq: None,
+        srq_listener: None,
+        term_char: b'\n',
+        term_char_enabled: false,
+        timeout: USBTMC_DEFAULT_TIMEOUT,
+        capabilities: Capabilities::default(),
+        vendor_id: device_info.vendor_id(),
+        product_id: device_info.product_id(),
+        serial_number: device_info.serial_number().map(str::to_string),
+        disconnected: AtomicBool::new(false),
+        reconnect_policy: None,
+        event_handler: None,
+    };
+
+    // some devices stall GET_CAPABILITIES, they still work without the optional features
+    usbtmc.capabilities = match get_capabilities_async(&usbtmc).await {
+        Ok(capabilities) => capabilities,
+        Err(error) => {
+            log!("GET_CAPABILITIES failed: {:?}, using the defaults\n", error);
+            Capabilities::default()
+        }
+    };
+    log!("Capabilities: {:?}\n", usbtmc.capabilities);
+
+    Ok(usbtmc)
 }
 
+/*
+* Finds the device again after it was unplugged or power-cycled, matching VID, PID and serial
+* number, and replaces the session with a fresh one.
+* The timeout, termination character, reconnect policy and event handler are kept, bTag starts
+* over and an installed SRQ handler has to be set again. Uses the reconnect policy of the session,
+* or the default policy if none is set.
+* If an init command fails the event handler gets ReconnectFailed and the error is returned, the
+* device is open again but has not been set up.
+*/
+pub fn reconnect(usbtmc: &mut Usbtmc) -> Result<(), UsbtmcErrors> {
+    block_on(reconnect_async(usbtmc))
+}
+
+pub async fn reconnect_async(usbtmc: &mut Usbtmc) -> Result<(), UsbtmcErrors> {
+    let policy = usbtmc.reconnect_policy.clone().unwrap_or_default();
+    let selector = usbtmc.serial_number.clone().map(DeviceSelector::Serial);
+    let mut last_error = UsbtmcErrors::DeviceNotFound;
+
+    for attempt in 0..policy.attempts {
+        if attempt > 0 {
+            Delay::new(policy.interval).await;
+        }
+
+        log!("Reconnect attempt {}\n", attempt + 1);
+        match open_usb(
+            usbtmc.vendor_id,
+            usbtmc.product_id,
+            selector.as_ref(),
+            Some(usbtmc.interface_number),
+        )
+        .await
+        {
+            Ok(mut fresh) => {
+                fresh.term_char = usbtmc.term_char;
+                fresh.term_char_enabled = usbtmc.term_char_enabled;
+                fresh.timeout = usbtmc.timeout;
+                fresh.reconnect_policy = usbtmc.reconnect_policy.take();
+                fresh.event_handler = usbtmc.event_handler.take();
+                *usbtmc = fresh;
+
+                for command in &policy.init_commands {
+                    if let Err(error) = write_async(usbtmc, command).await {
+                        notify_event(usbtmc, ConnectionEvent::ReconnectFailed);
+                        return Err(error);
+                    }
+                }
+
+                notify_event(usbtmc, ConnectionEvent::Reconnected);
+                return Ok(());
+            }
+            Err(error) => last_error = error,
+        }
+    }
+
+    notify_event(usbtmc, ConnectionEvent::ReconnectFailed);
+    Err(last_error)
+}
+
+/*
+* Returns the payload of a binary block response such as "#15hello", without the termination
+* character as returned by query_raw
+*/
 pub fn get_data_from_raw(raw_data: &[u8]) -> Result<&[u8], UsbtmcErrors> {
-    if raw_data[0] == b'#' {
-        let num_bytes = String::from_utf8(raw_data[1..2].to_vec())
-            .unwrap()
-            .parse::<usize>()
-            .unwrap();
-
-        if num_bytes > 0 {
-            let data_size_ascii = String::from_utf8(raw_data[2..(2 + num_bytes)].to_vec()).unwrap();
-            let data_size = data_size_ascii.parse::<usize>().unwrap();
-
-            if data_size != raw_data.len() - (2 + num_bytes) {
-                return Err(UsbtmcErrors::InvalidData);
+    // query_raw has already stripped the termination character, a stand-in lets the block parser
+    // check that nothing follows the block
+    const END: u8 = b'\n';
+
+    let mut state = BlockState::Header(Vec::new());
+    let mut data_size: usize = 0;
+    let mut count = |chunk: &[u8]| {
+        data_size += chunk.len();
+        Ok(())
+    };
+
+    parse_block_chunk(&mut state, raw_data, END, &mut count)?;
+    parse_block_chunk(&mut state, &[END], END, &mut count)?;
+    finish_block(&state, END)?;
+
+    Ok(&raw_data[raw_data.len() - data_size..])
+}
+
+pub fn query_binary_data<T: Transport>(
+    transport: &mut T,
+    command: &str,
+) -> Result<Vec<u8>, UsbtmcErrors> {
+    block_on(query_binary_data_async(transport, command))
+}
+
+pub async fn query_binary_data_async<T: Transport>(
+    transport: &mut T,
+    command: &str,
+) -> Result<Vec<u8>, UsbtmcErrors> {
+    let mut data: Vec<u8> = Vec::new();
+
+    query_binary_data_chunks_async(transport, command, |chunk: &[u8]| {
+        data.extend_from_slice(chunk);
+        Ok(())
+    })
+    .await?;
+
+    Ok(data)
+}
+
+/*
+* IEEE 488.2 arbitrary block, parsed as the transfers arrive so the payload never has to be held
+* in memory. `#0` indefinite length blocks run until the termination character.
+*/
+enum BlockState {
+    Header(Vec<u8>),
+    Definite(usize),
+    Indefinite(Option<u8>),
+    // true once the termination character after the block has arrived
+    Done(bool),
+}
+
+fn parse_block_chunk<F>(
+    state: &mut BlockState,
+    mut chunk: &[u8],
+    term_char: u8,
+    on_chunk: &mut F,
+) -> Result<(), UsbtmcErrors>
+where
+    F: FnMut(&[u8]) -> Result<(), UsbtmcErrors>,
+{
+    while !chunk.is_empty() {
+        match state {
+            BlockState::Header(header) => {
+                header.push(chunk[0]);
+                chunk = &chunk[1..];
+
+                if header[0] != b'#' {
+                    return Err(UsbtmcErrors::InvalidData);
+                }
+                if header.len() < 2 {
+                    continue;
+                }
+
+                let num_bytes = (header[1] as char)
+                    .to_digit(10)
+                    .ok_or(UsbtmcErrors::InvalidData)? as usize;
+
+                if num_bytes == 0 {
+                    *state = BlockState::Indefinite(None);
+                } else if header.len() == 2 + num_bytes {
+                    let data_size = std::str::from_utf8(&header[2..])
+                        .ok()
+                        .and_then(|ascii| ascii.parse::<usize>().ok())
+                        .ok_or(UsbtmcErrors::InvalidData)?;
+                    log!("Block data size: {}\n", data_size);
+
+                    *state = if data_size > 0 {
+                        BlockState::Definite(data_size)
+                    } else {
+                        BlockState::Done(false)
+                    };
+                }
+            }
+            BlockState::Definite(remaining) => {
+                let size = chunk.len().min(*remaining);
+                on_chunk(&chunk[..size])?;
+                chunk = &chunk[size..];
+
+                *remaining -= size;
+                if *remaining == 0 {
+                    *state = BlockState::Done(false);
+                }
+            }
+            BlockState::Indefinite(held) => {
+                // the last byte is held back until more data arrives, it may be the terminator
+                if let Some(byte) = held.take() {
+                    on_chunk(&[byte])?;
+                }
+                let (last, rest) = chunk.split_last().unwrap();
+                on_chunk(rest)?;
+                *held = Some(*last);
+                chunk = &[];
+            }
+            // only the termination character may follow the block
+            BlockState::Done(terminated) => {
+                if *terminated || chunk[0] != term_char {
+                    return Err(UsbtmcErrors::InvalidData);
+                }
+                *terminated = true;
+                chunk = &chunk[1..];
             }
         }
+    }
 
-        let data = &raw_data[(2 + num_bytes)..];
+    Ok(())
+}
 
-        Ok(data)
-    } else {
-        Err(UsbtmcErrors::InvalidData)
+/*
+* Checks that the response ended with the termination character right after the block, anything
+* else is a truncated or misframed response
+*/
+fn finish_block(state: &BlockState, term_char: u8) -> Result<(), UsbtmcErrors> {
+    match state {
+        BlockState::Done(true) => Ok(()),
+        BlockState::Indefinite(Some(held)) if *held == term_char => Ok(()),
+        _ => Err(UsbtmcErrors::InvalidData),
     }
 }
 
-pub fn query_binary_data(usbtmc: &mut Usbtmc, command: &str) -> Result<Vec<u8>, UsbtmcErrors> {
-    let data_raw = query_raw(usbtmc, command)?;
+/*
+* Sends `command` and passes the payload of the binary block response to `on_chunk` as it
+* arrives. Returns the number of payload bytes.
+*/
+pub fn query_binary_data_chunks<T, F>(
+    transport: &mut T,
+    command: &str,
+    on_chunk: F,
+) -> Result<usize, UsbtmcErrors>
+where
+    T: Transport,
+    F: FnMut(&[u8]) -> Result<(), UsbtmcErrors> + Send,
+{
+    block_on(query_binary_data_chunks_async(transport, command, on_chunk))
+}
 
-    let data = get_data_from_raw(&data_raw)?;
+pub async fn query_binary_data_chunks_async<T, F>(
+    transport: &mut T,
+    command: &str,
+    mut on_chunk: F,
+) -> Result<usize, UsbtmcErrors>
+where
+    T: Transport,
+    F: FnMut(&[u8]) -> Result<(), UsbtmcErrors> + Send,
+{
+    let mut state = BlockState::Header(Vec::new());
+    let mut total: usize = 0;
+    let term_char = transport.term_char();
 
-    Ok(data.to_vec())
+    let mut on_counted_chunk = |chunk: &[u8]| {
+        total += chunk.len();
+        on_chunk(chunk)
+    };
+
+    let command_with_newline = command.to_owned() + "\n";
+
+    log!("Sending command: {:?}\n", command);
+    transport
+        .write_message(command_with_newline.as_bytes())
+        .await?;
+
+    transport
+        .read_block_message(&mut |payload: &[u8]| {
+            parse_block_chunk(&mut state, payload, term_char, &mut on_counted_chunk)
+        })
+        .await?;
+
+    finish_block(&state, term_char)?;
+
+    Ok(total)
+}
+
+/*
+* Like query_binary_data, but writes the payload to `writer` as it arrives
+*/
+pub fn query_binary_data_to<T: Transport, W: Write + Send>(
+    transport: &mut T,
+    command: &str,
+    writer: &mut W,
+) -> Result<usize, UsbtmcErrors> {
+    query_binary_data_chunks(transport, command, |chunk| {
+        writer.write_all(chunk).map_err(UsbtmcErrors::Io)
+    })
 }
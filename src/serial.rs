@@ -0,0 +1,426 @@
+/*
+* SCPI over RS-232 or a USB-CDC virtual COM port. Like a raw socket there is no EOM, a response ends
+* at the read terminator unless it is inside a # definite length block.
+*
+* On Unix the tty is driven by async-io like the LAN transports. Elsewhere the port is blocking,
+* each read and write runs on a thread of its own so the executor is never held up.
+*/
+
+use crate::tcp::{io_error, scan_response, ScanState};
+use crate::transport::Transport;
+use crate::usbtmc::{timeout_after, UsbtmcErrors};
+use futures_timer::Delay;
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use async_io::Async;
+#[cfg(unix)]
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
+use std::mem::ManuallyDrop;
+#[cfg(unix)]
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd};
+
+#[cfg(not(unix))]
+use std::sync::{Arc, Mutex};
+#[cfg(not(unix))]
+use std::task::{Poll, Waker};
+
+pub use serialport::{DataBits, FlowControl, Parity, StopBits};
+
+const SERIAL_DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const SERIAL_READ_BUFFER_SIZE: usize = 4096;
+
+pub struct SerialSettings {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    // sent in place of the '\n' that ends every command
+    pub write_terminator: Vec<u8>,
+    // ends every response, the last byte is the term_char seen by the SCPI helpers
+    pub read_terminator: Vec<u8>,
+    // minimum time between the end of one exchange and the next command
+    pub inter_command_delay: Duration,
+    pub timeout: Duration,
+}
+
+/*
+* 9600 baud 8N1 without flow control, commands and responses end with '\n'
+*/
+impl Default for SerialSettings {
+    fn default() -> Self {
+        SerialSettings {
+            baud_rate: 9600,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            write_terminator: b"\n".to_vec(),
+            read_terminator: b"\n".to_vec(),
+            inter_command_delay: Duration::ZERO,
+            timeout: SERIAL_DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/*
+* The tty with plain read(2) and write(2). TTYPort polls with a timeout of its own before every
+* transfer, here async-io waits for the descriptor instead.
+*/
+#[cfg(unix)]
+struct Tty(serialport::TTYPort);
+
+#[cfg(unix)]
+type Port = Async<Tty>;
+#[cfg(not(unix))]
+type Port = Box<dyn serialport::SerialPort>;
+
+pub struct Serial {
+    port: Port,
+    path: String,
+    timeout: Duration,
+    write_terminator: Vec<u8>,
+    read_terminator: Vec<u8>,
+    inter_command_delay: Duration,
+    last_exchange: Option<Instant>,
+    // bytes received after the end of the last response
+    pending: Vec<u8>,
+}
+
+macro_rules! log {
+    // The `$(...)*` syntax is used to match against any number of arguments of any type
+    ($($arg:tt)*) => {
+        // Check if in debug mode and call `print!` if true
+        if cfg!(debug_assertions) {
+            print!($($arg)*);
+        }
+    };
+}
+
+fn serial_error(error: serialport::Error) -> UsbtmcErrors {
+    match error.kind() {
+        serialport::ErrorKind::NoDevice => UsbtmcErrors::DeviceNotFound,
+        _ => UsbtmcErrors::Io(error.into()),
+    }
+}
+
+#[cfg(unix)]
+impl Tty {
+    fn file(&self) -> ManuallyDrop<File> {
+        // the descriptor stays owned by the TTYPort
+        ManuallyDrop::new(unsafe { File::from_raw_fd(self.0.as_raw_fd()) })
+    }
+}
+
+#[cfg(unix)]
+impl AsFd for Tty {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.0.as_raw_fd()) }
+    }
+}
+
+#[cfg(unix)]
+impl Read for Tty {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        self.file().read(buffer)
+    }
+}
+
+#[cfg(unix)]
+impl Write for Tty {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.file().write(data)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// the TTYPort is never replaced or closed while it is registered with async-io
+#[cfg(unix)]
+unsafe impl async_io::IoSafe for Tty {}
+
+/*
+* Runs blocking port I/O on a thread of its own and waits for it without blocking the executor
+*/
+#[cfg(not(unix))]
+async fn unblock<T, F>(work: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let slot = Arc::new(Mutex::new((None, None::<Waker>)));
+    let worker_slot = slot.clone();
+
+    std::thread::spawn(move || {
+        let value = work();
+        let mut slot = worker_slot.lock().unwrap();
+        slot.0 = Some(value);
+        if let Some(waker) = slot.1.take() {
+            waker.wake();
+        }
+    });
+
+    futures_lite::future::poll_fn(|context| {
+        let mut slot = slot.lock().unwrap();
+        match slot.0.take() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                slot.1 = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+#[cfg(unix)]
+fn open_port(builder: serialport::SerialPortBuilder) -> Result<Port, UsbtmcErrors> {
+    let port = builder.open_native().map_err(serial_error)?;
+    Async::new(Tty(port)).map_err(io_error)
+}
+
+#[cfg(not(unix))]
+fn open_port(builder: serialport::SerialPortBuilder) -> Result<Port, UsbtmcErrors> {
+    builder.open().map_err(serial_error)
+}
+
+// the port for settings that do not touch its I/O
+#[cfg(unix)]
+fn native_port(serial: &mut Serial) -> &mut dyn serialport::SerialPort {
+    // the TTYPort is only reconfigured, never replaced
+    unsafe { &mut serial.port.get_mut().0 }
+}
+
+#[cfg(not(unix))]
+fn native_port(serial: &mut Serial) -> &mut dyn serialport::SerialPort {
+    serial.port.as_mut()
+}
+
+#[cfg(unix)]
+async fn port_write(serial: &mut Serial, data: &[u8]) -> Result<(), UsbtmcErrors> {
+    serial.port.write_all(data).await.map_err(io_error)
+}
+
+#[cfg(not(unix))]
+async fn port_write(serial: &mut Serial, data: &[u8]) -> Result<(), UsbtmcErrors> {
+    let mut port = serial.port.try_clone().map_err(serial_error)?;
+    let data = data.to_vec();
+
+    unblock(move || port.write_all(&data).and_then(|_| port.flush()))
+        .await
+        .map_err(io_error)
+}
+
+#[cfg(unix)]
+async fn port_read(serial: &mut Serial, buffer: &mut [u8]) -> Result<usize, UsbtmcErrors> {
+    serial.port.read(buffer).await.map_err(io_error)
+}
+
+#[cfg(not(unix))]
+async fn port_read(serial: &mut Serial, buffer: &mut [u8]) -> Result<usize, UsbtmcErrors> {
+    let mut port = serial.port.try_clone().map_err(serial_error)?;
+    let size = buffer.len();
+
+    let (result, chunk) = unblock(move || {
+        let mut chunk = vec![0u8; size];
+        let result = port.read(&mut chunk);
+        (result, chunk)
+    })
+    .await;
+    let size = result.map_err(io_error)?;
+    buffer[..size].copy_from_slice(&chunk[..size]);
+
+    Ok(size)
+}
+
+/*
+* Opens a port such as "/dev/ttyUSB0" or "COM3"
+*/
+pub fn open_serial(path: &str, settings: &SerialSettings) -> Result<Serial, UsbtmcErrors> {
+    if settings.read_terminator.is_empty() || settings.write_terminator.is_empty() {
+        return Err(UsbtmcErrors::Io(ErrorKind::InvalidInput.into()));
+    }
+
+    let port = open_port(
+        serialport::new(path, settings.baud_rate)
+            .data_bits(settings.data_bits)
+            .parity(settings.parity)
+            .stop_bits(settings.stop_bits)
+            .flow_control(settings.flow_control)
+            .timeout(settings.timeout),
+    )?;
+
+    log!("Opened {} at {} baud\n", path, settings.baud_rate);
+
+    Ok(Serial {
+        port,
+        path: path.to_string(),
+        timeout: settings.timeout,
+        write_terminator: settings.write_terminator.clone(),
+        read_terminator: settings.read_terminator.clone(),
+        inter_command_delay: settings.inter_command_delay,
+        last_exchange: None,
+        pending: Vec::new(),
+    })
+}
+
+/*
+* Sets the bytes that end commands and responses, for example b"\r\n" for both
+*/
+pub fn set_serial_terminators(
+    serial: &mut Serial,
+    write_terminator: &[u8],
+    read_terminator: &[u8],
+) -> Result<(), UsbtmcErrors> {
+    if read_terminator.is_empty() || write_terminator.is_empty() {
+        return Err(UsbtmcErrors::Io(ErrorKind::InvalidInput.into()));
+    }
+
+    serial.write_terminator = write_terminator.to_vec();
+    serial.read_terminator = read_terminator.to_vec();
+
+    Ok(())
+}
+
+/*
+* Slow instruments drop characters when a command follows the previous response too quickly
+*/
+pub fn set_inter_command_delay(serial: &mut Serial, delay: Duration) {
+    serial.inter_command_delay = delay;
+}
+
+pub fn set_baud_rate(serial: &mut Serial, baud_rate: u32) -> Result<(), UsbtmcErrors> {
+    native_port(serial)
+        .set_baud_rate(baud_rate)
+        .map_err(serial_error)
+}
+
+impl Transport for Serial {
+    /*
+     * The trailing '\n' added by the SCPI helpers is replaced with the write terminator
+     */
+    async fn write_message(&mut self, data: &[u8]) -> Result<(), UsbtmcErrors> {
+        if let Some(last_exchange) = self.last_exchange {
+            let elapsed = last_exchange.elapsed();
+            if elapsed < self.inter_command_delay {
+                Delay::new(self.inter_command_delay - elapsed).await;
+            }
+        }
+
+        let message = match data.strip_suffix(b"\n") {
+            Some(command) => [command, &self.write_terminator].concat(),
+            None => data.to_vec(),
+        };
+
+        log!("Sending {} bytes to {}\n", message.len(), self.path);
+
+        let timeout = self.timeout;
+        let result = timeout_after(port_write(self, &message), timeout)
+            .await
+            .and_then(|result| result);
+        self.last_exchange = Some(Instant::now());
+
+        result
+    }
+
+    /*
+     * Only the last byte of the read terminator goes to `on_payload`, the bytes before it are
+     * dropped so the SCPI helpers see a single termination character. The whole response has to
+     * arrive within the timeout, however slowly the device sends it.
+     */
+    async fn read_message<F>(&mut self, on_payload: &mut F) -> Result<(), UsbtmcErrors>
+    where
+        F: FnMut(&[u8]) -> Result<(), UsbtmcErrors> + Send,
+    {
+        let term_char = self.term_char();
+        let prefix_length = self.read_terminator.len() - 1;
+        let mut state = ScanState::new();
+        let mut buffer = std::mem::take(&mut self.pending);
+        // bytes at the start of `buffer` that were scanned but held back, they may start the
+        // read terminator
+        let mut scanned = 0;
+
+        let timeout = self.timeout;
+        let receive = async {
+            loop {
+                if buffer.len() == scanned {
+                    let mut chunk = [0u8; SERIAL_READ_BUFFER_SIZE];
+                    let size = match port_read(self, &mut chunk).await {
+                        Ok(0) => break Err(UsbtmcErrors::Io(ErrorKind::UnexpectedEof.into())),
+                        Ok(size) => size,
+                        Err(error) => break Err(error),
+                    };
+                    buffer.extend_from_slice(&chunk[..size]);
+                }
+
+                match scan_response(&mut state, &buffer[scanned..], term_char) {
+                    Some(end) => {
+                        let end = scanned + end;
+                        let mut response = &buffer[..end - 1];
+                        if response.ends_with(&self.read_terminator[..prefix_length]) {
+                            response = &response[..response.len() - prefix_length];
+                        }
+                        if let Err(error) =
+                            on_payload(response).and_then(|_| on_payload(&[term_char]))
+                        {
+                            break Err(error);
+                        }
+                        self.pending = buffer.split_off(end);
+                        break Ok(());
+                    }
+                    None => {
+                        let keep = buffer.len().min(prefix_length);
+                        let ready = buffer.len() - keep;
+                        if ready > 0 {
+                            if let Err(error) = on_payload(&buffer[..ready]) {
+                                break Err(error);
+                            }
+                            buffer.drain(..ready);
+                        }
+                        scanned = buffer.len();
+                    }
+                }
+            }
+        };
+        let result = timeout_after(receive, timeout)
+            .await
+            .and_then(|result| result);
+        self.last_exchange = Some(Instant::now());
+
+        result
+    }
+
+    /*
+     * A serial port has no device clear, this only discards what is buffered on both sides of
+     * the port
+     */
+    async fn clear(&mut self) -> Result<(), UsbtmcErrors> {
+        log!("Clearing {}\n", self.path);
+
+        self.pending.clear();
+        native_port(self)
+            .clear(serialport::ClearBuffer::All)
+            .map_err(serial_error)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+        // a blocking port gives up on a single transfer after this
+        let _ = native_port(self).set_timeout(timeout);
+    }
+
+    fn term_char(&self) -> u8 {
+        self.read_terminator[self.read_terminator.len() - 1]
+    }
+}
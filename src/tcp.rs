@@ -0,0 +1,303 @@
+/*
+* Raw SCPI over a TCP socket, usually port 5025. There is no EOM on a socket, a response ends at
+* the termination character unless it is inside a # definite length block.
+*
+* The socket helpers here are shared with VXI-11 and HiSLIP, io_error and scan_response with serial
+* ports.
+*/
+
+use crate::transport::Transport;
+use crate::usbtmc::{timeout_after, UsbtmcErrors};
+use async_io::Async;
+use futures_lite::future::block_on;
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+pub const SCPI_RAW_PORT: u16 = 5025;
+
+const TCP_DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const TCP_READ_BUFFER_SIZE: usize = 64 * 1024;
+
+pub struct TcpSocket {
+    stream: Async<TcpStream>,
+    address: SocketAddr,
+    connect_timeout: Duration,
+    timeout: Duration,
+    term_char: u8,
+    // bytes received after the end of the last response
+    pending: Vec<u8>,
+}
+
+macro_rules! log {
+    // The `$(...)*` syntax is used to match against any number of arguments of any type
+    ($($arg:tt)*) => {
+        // Check if in debug mode and call `print!` if true
+        if cfg!(debug_assertions) {
+            print!($($arg)*);
+        }
+    };
+}
+
+pub(crate) fn io_error(error: std::io::Error) -> UsbtmcErrors {
+    match error.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => UsbtmcErrors::Timeout,
+        _ => UsbtmcErrors::Io(error),
+    }
+}
+
+/*
+* Connects to "host" or "host:port", the port defaults to 5025
+*/
+pub fn open_socket(address: &str) -> Result<TcpSocket, UsbtmcErrors> {
+    block_on(open_socket_async(address))
+}
+
+pub async fn open_socket_async(address: &str) -> Result<TcpSocket, UsbtmcErrors> {
+    open_socket_with_timeout_async(address, TCP_DEFAULT_TIMEOUT).await
+}
+
+pub fn open_socket_with_timeout(
+    address: &str,
+    connect_timeout: Duration,
+) -> Result<TcpSocket, UsbtmcErrors> {
+    block_on(open_socket_with_timeout_async(address, connect_timeout))
+}
+
+pub async fn open_socket_with_timeout_async(
+    address: &str,
+    connect_timeout: Duration,
+) -> Result<TcpSocket, UsbtmcErrors> {
+    let mut last_error = UsbtmcErrors::DeviceNotFound;
+    for socket_address in resolve(address, SCPI_RAW_PORT)? {
+        match connect(socket_address, connect_timeout).await {
+            Ok(stream) => {
+                log!("Connected to {}\n", socket_address);
+
+                return Ok(TcpSocket {
+                    stream,
+                    address: socket_address,
+                    connect_timeout,
+                    timeout: TCP_DEFAULT_TIMEOUT,
+                    term_char: b'\n',
+                    pending: Vec::new(),
+                });
+            }
+            Err(error) => last_error = error,
+        }
+    }
+
+    Err(last_error)
+}
+
+/*
+* Resolves "host" or "host:port", `default_port` is used when the address has none
+*/
+pub(crate) fn resolve(address: &str, default_port: u16) -> Result<Vec<SocketAddr>, UsbtmcErrors> {
+    match address.to_socket_addrs() {
+        Ok(addresses) => Ok(addresses.collect()),
+        Err(_) => Ok((address, default_port)
+            .to_socket_addrs()
+            .map_err(UsbtmcErrors::Io)?
+            .collect()),
+    }
+}
+
+/*
+* The stream is non-blocking and driven by async-io. Every read and write on it is wrapped in
+* timeout_after, so a silent instrument gives a Timeout instead of hanging the caller.
+*/
+pub(crate) async fn connect(
+    address: SocketAddr,
+    connect_timeout: Duration,
+) -> Result<Async<TcpStream>, UsbtmcErrors> {
+    let stream = timeout_after(Async::<TcpStream>::connect(address), connect_timeout)
+        .await?
+        .map_err(io_error)?;
+
+    stream
+        .get_ref()
+        .set_nodelay(true)
+        .map_err(UsbtmcErrors::Io)?;
+
+    Ok(stream)
+}
+
+/*
+* Sets the character that terminates responses. Defaults to '\n'
+*/
+pub fn set_socket_term_char(socket: &mut TcpSocket, term_char: u8) {
+    socket.term_char = term_char;
+}
+
+/*
+* Finds the end of a response in the bytes received so far. A '#' followed by a non-zero digit
+* starts a definite length block whose payload may contain the termination character. IEEE 488.2
+* section 8.4 only allows a block where a data element starts, at the beginning of the response or
+* after a header or data separator, and never inside a quoted string.
+*/
+pub(crate) enum ScanState {
+    Text { element_start: bool },
+    Quoted,
+    Hash,
+    Digits { left: u32, length: usize },
+    Block(usize),
+}
+
+impl ScanState {
+    pub(crate) fn new() -> ScanState {
+        ScanState::Text {
+            element_start: true,
+        }
+    }
+}
+
+pub(crate) fn scan_response(state: &mut ScanState, data: &[u8], term_char: u8) -> Option<usize> {
+    let mut i = 0;
+
+    while i < data.len() {
+        match state {
+            ScanState::Text { element_start } => {
+                if data[i] == term_char {
+                    return Some(i + 1);
+                }
+                *state = match data[i] {
+                    b'#' if *element_start => ScanState::Hash,
+                    b'"' => ScanState::Quoted,
+                    byte => ScanState::Text {
+                        element_start: matches!(byte, b',' | b';' | b' '),
+                    },
+                };
+                i += 1;
+            }
+            ScanState::Quoted => {
+                // an unbalanced quote must not hide the end of the response
+                if data[i] == term_char {
+                    return Some(i + 1);
+                }
+                if data[i] == b'"' {
+                    *state = ScanState::Text {
+                        element_start: false,
+                    };
+                }
+                i += 1;
+            }
+            ScanState::Hash => match (data[i] as char).to_digit(10) {
+                // #0 is an indefinite length block, it runs until the termination character
+                Some(0) | None => {
+                    *state = ScanState::Text {
+                        element_start: false,
+                    }
+                }
+                Some(digits) => {
+                    *state = ScanState::Digits {
+                        left: digits,
+                        length: 0,
+                    };
+                    i += 1;
+                }
+            },
+            ScanState::Digits { left, length } => match (data[i] as char).to_digit(10) {
+                Some(digit) => {
+                    *length = *length * 10 + digit as usize;
+                    *left -= 1;
+                    if *left == 0 {
+                        *state = ScanState::Block(*length);
+                    }
+                    i += 1;
+                }
+                None => {
+                    *state = ScanState::Text {
+                        element_start: false,
+                    }
+                }
+            },
+            ScanState::Block(remaining) => {
+                let size = (data.len() - i).min(*remaining);
+                i += size;
+                *remaining -= size;
+                if *remaining == 0 {
+                    *state = ScanState::Text {
+                        element_start: false,
+                    };
+                }
+            }
+        }
+    }
+
+    None
+}
+
+impl Transport for TcpSocket {
+    async fn write_message(&mut self, data: &[u8]) -> Result<(), UsbtmcErrors> {
+        log!("Sending {} bytes to {}\n", data.len(), self.address);
+
+        let stream = &mut self.stream;
+        let write = async move {
+            stream.write_all(data).await?;
+            stream.flush().await
+        };
+
+        timeout_after(write, self.timeout).await?.map_err(io_error)
+    }
+
+    async fn read_message<F>(&mut self, on_payload: &mut F) -> Result<(), UsbtmcErrors>
+    where
+        F: FnMut(&[u8]) -> Result<(), UsbtmcErrors> + Send,
+    {
+        let mut state = ScanState::new();
+        let mut buffer = std::mem::take(&mut self.pending);
+
+        loop {
+            if buffer.is_empty() {
+                buffer.resize(TCP_READ_BUFFER_SIZE, 0);
+                let size = timeout_after(self.stream.read(&mut buffer), self.timeout)
+                    .await?
+                    .map_err(io_error)?;
+                if size == 0 {
+                    return Err(UsbtmcErrors::Io(ErrorKind::UnexpectedEof.into()));
+                }
+                buffer.truncate(size);
+            }
+
+            match scan_response(&mut state, &buffer, self.term_char) {
+                Some(end) => {
+                    on_payload(&buffer[..end])?;
+                    self.pending = buffer.split_off(end);
+                    return Ok(());
+                }
+                None => {
+                    on_payload(&buffer)?;
+                    buffer.clear();
+                }
+            }
+        }
+    }
+
+    /*
+     * Raw sockets have no device clear. Reconnecting drops any unread output, and the instrument
+     * abandons a response to a closed connection.
+     */
+    async fn clear(&mut self) -> Result<(), UsbtmcErrors> {
+        log!("Clearing {}\n", self.address);
+
+        self.pending.clear();
+        let _ = self.stream.get_ref().shutdown(std::net::Shutdown::Both);
+        self.stream = connect(self.address, self.connect_timeout).await?;
+
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn term_char(&self) -> u8 {
+        self.term_char
+    }
+}
@@ -0,0 +1,60 @@
+/*
+* A connection to an instrument that carries SCPI messages. The helpers in lib.rs such as query,
+* write and query_binary_data work over any transport.
+*
+* The futures are Send, so measurement code written once against Transport can run on a
+* multi-threaded executor such as tokio::spawn.
+*/
+
+use crate::usbtmc::UsbtmcErrors;
+use std::future::Future;
+use std::time::Duration;
+
+pub trait Transport {
+    /*
+     * Sends one complete message, including its termination character
+     */
+    fn write_message(
+        &mut self,
+        data: &[u8],
+    ) -> impl Future<Output = Result<(), UsbtmcErrors>> + Send;
+
+    /*
+     * Reads one complete response and passes it to `on_payload` as it arrives, including the
+     * termination character
+     */
+    fn read_message<F>(
+        &mut self,
+        on_payload: &mut F,
+    ) -> impl Future<Output = Result<(), UsbtmcErrors>> + Send
+    where
+        F: FnMut(&[u8]) -> Result<(), UsbtmcErrors> + Send;
+
+    /*
+     * Reads a response that holds a binary block. The payload may contain the termination
+     * character, so a transport must not let the instrument end the response on it.
+     */
+    fn read_block_message<F>(
+        &mut self,
+        on_payload: &mut F,
+    ) -> impl Future<Output = Result<(), UsbtmcErrors>> + Send
+    where
+        F: FnMut(&[u8]) -> Result<(), UsbtmcErrors> + Send,
+    {
+        self.read_message(on_payload)
+    }
+
+    /*
+     * Clears the input and output buffers of the instrument, like a GPIB device clear
+     */
+    fn clear(&mut self) -> impl Future<Output = Result<(), UsbtmcErrors>> + Send;
+
+    fn timeout(&self) -> Duration;
+
+    fn set_timeout(&mut self, timeout: Duration);
+
+    /*
+     * The character that ends every response
+     */
+    fn term_char(&self) -> u8;
+}
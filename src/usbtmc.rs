@@ -2,20 +2,214 @@
 *
 */
 
+use crate::transport::Transport;
 use crate::Usbtmc;
 use byteorder::{ByteOrder, LittleEndian};
+use futures_lite::future;
 use futures_lite::future::block_on;
+use futures_timer::Delay;
+use nusb::transfer::ControlIn;
+use nusb::transfer::ControlType;
+use nusb::transfer::Recipient;
 use nusb::transfer::RequestBuffer;
 use nusb::transfer::TransferError;
+use nusb::Speed;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
 
 const USBTMC_MSGID_DEV_DEP_MSG_OUT: u8 = 1;
 const USBTMC_MSGID_DEV_DEP_MSG_IN: u8 = 2;
+const USBTMC_MSGID_VENDOR_SPECIFIC_OUT: u8 = 126;
+const USBTMC_MSGID_VENDOR_SPECIFIC_IN: u8 = 127;
+const USB488_MSGID_TRIGGER: u8 = 128;
+
+/*
+* USBTMC document Table 15
+*/
+const USBTMC_REQUEST_INITIATE_ABORT_BULK_OUT: u8 = 1;
+const USBTMC_REQUEST_CHECK_ABORT_BULK_OUT_STATUS: u8 = 2;
+const USBTMC_REQUEST_INITIATE_ABORT_BULK_IN: u8 = 3;
+const USBTMC_REQUEST_CHECK_ABORT_BULK_IN_STATUS: u8 = 4;
+const USBTMC_REQUEST_INITIATE_CLEAR: u8 = 5;
+const USBTMC_REQUEST_CHECK_CLEAR_STATUS: u8 = 6;
+const USBTMC_REQUEST_GET_CAPABILITIES: u8 = 7;
+
+/*
+* USB488 document Table 9
+*/
+const USB488_REQUEST_READ_STATUS_BYTE: u8 = 128;
+const USB488_REQUEST_REN_CONTROL: u8 = 160;
+const USB488_REQUEST_GO_TO_LOCAL: u8 = 161;
+const USB488_REQUEST_LOCAL_LOCKOUT: u8 = 162;
+
+/*
+* USBTMC document Table 16 and USB488 document Table 10
+*/
+const USBTMC_STATUS_SUCCESS: u8 = 0x01;
+const USBTMC_STATUS_PENDING: u8 = 0x02;
+const USBTMC_STATUS_FAILED: u8 = 0x80;
+const USBTMC_STATUS_TRANSFER_NOT_IN_PROGRESS: u8 = 0x81;
+const USBTMC_STATUS_SPLIT_NOT_IN_PROGRESS: u8 = 0x82;
+const USBTMC_STATUS_SPLIT_IN_PROGRESS: u8 = 0x83;
+const USB488_STATUS_INTERRUPT_IN_BUSY: u8 = 0x20;
+
+/*
+* USB488 document Table 6 and Table 7
+*/
+const USB488_NOTIFY_STATUS_BYTE: u8 = 0x80;
+const USB488_NOTIFY_SRQ: u8 = 0x81;
+
+const USBTMC_CHECK_STATUS_INTERVAL: Duration = Duration::from_millis(10);
+
+const USBTMC_QUEUED_TRANSFERS_PER_MESSAGE: usize = 16;
+
+pub(crate) const USBTMC_DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/*
+* USBTMC document Table 43 and USB488 document Table 1
+*/
+pub(crate) const USBTMC_INTERFACE_CLASS: u8 = 0xFE;
+pub(crate) const USBTMC_INTERFACE_SUBCLASS: u8 = 0x03;
+const USBTMC_INTERFACE_PROTOCOL_USB488: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Usbtmc,
+    Usb488,
+}
+
+impl Protocol {
+    pub(crate) fn from_interface_protocol(protocol: u8) -> Protocol {
+        if protocol == USBTMC_INTERFACE_PROTOCOL_USB488 {
+            Protocol::Usb488
+        } else {
+            Protocol::Usbtmc
+        }
+    }
+}
+
+pub(crate) fn is_usbtmc_interface(class: u8, subclass: u8) -> bool {
+    class == USBTMC_INTERFACE_CLASS && subclass == USBTMC_INTERFACE_SUBCLASS
+}
+
+/*
+* USBTMC document Table 37 and USB488 document Table 8
+*/
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Capabilities {
+    pub bcd_usbtmc: u16,
+    pub indicator_pulse: bool,
+    pub talk_only: bool,
+    pub listen_only: bool,
+    pub term_char: bool,
+    pub bcd_usb488: u16,
+    pub usb488_2: bool,
+    pub ren_control: bool,
+    pub trigger: bool,
+    pub scpi: bool,
+    pub sr1: bool,
+    pub rl1: bool,
+    pub dt1: bool,
+}
 
 #[derive(Debug)]
 pub enum UsbtmcErrors {
     BulkOutTransferError,
     BulkInTransferError,
+    ControlTransferError,
     InvalidData,
+    MsgIdMismatch(u8),
+    BtagMismatch(u8),
+    BtagInverseMismatch,
+    StatusFailed,
+    StatusTransferNotInProgress,
+    StatusSplitNotInProgress,
+    StatusSplitInProgress,
+    StatusInterruptInBusy,
+    StatusUnknown(u8),
+    InterruptInTransferError,
+    NoInterruptEndpoint,
+    SrqHandlerInstalled,
+    NotSupported,
+    NoUsbtmcInterface,
+    Disconnected,
+    DeviceNotFound,
+    MultipleDevices(usize),
+    EndpointNotFound,
+    InvalidResource(String),
+    RpcError,
+    Vxi11Error(u32),
+    HislipError(u8),
+    LockFailed,
+    Timeout,
+    Io(std::io::Error),
+}
+
+/*
+* Reported to the handler installed with set_event_handler
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Disconnected,
+    Reconnected,
+    ReconnectFailed,
+}
+
+/*
+* How to bring the session back after the device disappears, see set_reconnect_policy.
+* `init_commands` are written to the device after every successful reconnect.
+*/
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub attempts: u32,
+    pub interval: Duration,
+    pub init_commands: Vec<String>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            attempts: 10,
+            interval: Duration::from_secs(1),
+            init_commands: Vec::new(),
+        }
+    }
+}
+
+pub(crate) type EventHandler = Mutex<Box<dyn FnMut(ConnectionEvent) + Send>>;
+
+#[derive(Default)]
+struct StopSignal {
+    stopped: bool,
+    waker: Option<Waker>,
+}
+
+/*
+* Owns the interrupt-IN endpoint while an SRQ handler is installed. SRQ notifications go to the
+* handler, everything else is forwarded to `notifications` for read_status_byte.
+*/
+pub(crate) struct SrqListener {
+    stop: Arc<Mutex<StopSignal>>,
+    // behind a Mutex so Usbtmc stays Sync and the async API can be used from multi-threaded runtimes
+    notifications: Mutex<Receiver<Vec<u8>>>,
+}
+
+impl Drop for SrqListener {
+    fn drop(&mut self) {
+        let mut stop = self.stop.lock().unwrap();
+        stop.stopped = true;
+        if let Some(waker) = stop.waker.take() {
+            waker.wake();
+        }
+    }
 }
 
 macro_rules! log {
@@ -56,14 +250,19 @@ fn little_write_u32(size: u32, len: u8) -> Vec<u8> {
     buf
 }
 
+/*
+* USBTMC document Table 2. bTag runs from 1 to 255 for the whole session and is never 0
+*/
+fn next_btag(usbtmc: &mut Usbtmc) -> u8 {
+    usbtmc.btag = (usbtmc.btag % 255) + 1;
+
+    usbtmc.btag
+}
+
 /*
 * USBTMC document Table 1
 */
 fn pack_bulk_out_header(msgid: u8, btag: u8) -> Vec<u8> {
-    //let last_btag: u8 = 0x00;
-    let btag: u8 = (btag % 255) + 1;
-    //last_btag = btag;
-
     // BBBx
     vec![msgid, btag, !btag, 0x00]
 }
@@ -89,21 +288,534 @@ fn pack_dev_dep_msg_out_header(transfer_size: usize, eom: bool, btag: u8) -> Vec
 /*
 * USBTMC document Table 4
 */
-fn pack_dev_dep_msg_in_header(transfer_size: usize, term_char: u8) -> Vec<u8> {
-    let mut header = pack_bulk_out_header(USBTMC_MSGID_DEV_DEP_MSG_IN, 0x00);
+fn pack_dev_dep_msg_in_header(transfer_size: usize, term_char: Option<u8>, btag: u8) -> Vec<u8> {
+    let mut header = pack_bulk_out_header(USBTMC_MSGID_DEV_DEP_MSG_IN, btag);
 
     let mut max_transfer_size: Vec<u8> = little_write_u32(transfer_size as u32, 4);
-    let bm_transfer_attributes: u8 = if term_char == 0 { 0x00 } else { 0x02 };
+    let bm_transfer_attributes: u8 = if term_char.is_none() { 0x00 } else { 0x02 };
 
     header.append(&mut max_transfer_size);
     header.push(bm_transfer_attributes);
-    header.push(term_char);
+    header.push(term_char.unwrap_or(0x00));
     header.append(&mut vec![0x00; 2]);
 
     header
 }
 
-fn is_query(data: &[u8]) -> bool {
+/*
+* USBTMC document Table 16 and USB488 document Table 10. SUCCESS and PENDING are passed back to the caller,
+* every other status is reported as an error.
+*/
+pub fn check_status(status: u8) -> Result<u8, UsbtmcErrors> {
+    match status {
+        USBTMC_STATUS_SUCCESS | USBTMC_STATUS_PENDING => Ok(status),
+        USBTMC_STATUS_FAILED => Err(UsbtmcErrors::StatusFailed),
+        USBTMC_STATUS_TRANSFER_NOT_IN_PROGRESS => Err(UsbtmcErrors::StatusTransferNotInProgress),
+        USBTMC_STATUS_SPLIT_NOT_IN_PROGRESS => Err(UsbtmcErrors::StatusSplitNotInProgress),
+        USBTMC_STATUS_SPLIT_IN_PROGRESS => Err(UsbtmcErrors::StatusSplitInProgress),
+        USB488_STATUS_INTERRUPT_IN_BUSY => Err(UsbtmcErrors::StatusInterruptInBusy),
+        _ => Err(UsbtmcErrors::StatusUnknown(status)),
+    }
+}
+
+/*
+* What an abort or clear does next after a CHECK_ABORT_BULK_OUT_STATUS, CHECK_ABORT_BULK_IN_STATUS or
+* CHECK_CLEAR_STATUS response
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusPoll {
+    Done,
+    // the Bulk-IN FIFO still holds data that has to be read before the status can change
+    DrainBulkIn,
+    Wait,
+}
+
+/*
+* USBTMC document Table 25, Table 27 and Table 33. D0 of the second byte is bmAbortBulkIn or
+* bmClear, it is reserved in CHECK_ABORT_BULK_OUT_STATUS
+*/
+pub fn poll_check_status(response: &[u8]) -> Result<StatusPoll, UsbtmcErrors> {
+    if response.len() < 2 {
+        return Err(UsbtmcErrors::InvalidData);
+    }
+
+    if check_status(response[0])? == USBTMC_STATUS_SUCCESS {
+        Ok(StatusPoll::Done)
+    } else if response[1] & 0x01 != 0 {
+        Ok(StatusPoll::DrainBulkIn)
+    } else {
+        Ok(StatusPoll::Wait)
+    }
+}
+
+/*
+* Waits for `transfer` for at most `timeout`. Dropping a nusb TransferFuture cancels the transfer.
+* futures-timer keeps this independent of any async runtime.
+*/
+pub(crate) async fn timeout_after<F: Future>(
+    transfer: F,
+    timeout: Duration,
+) -> Result<F::Output, UsbtmcErrors> {
+    future::or(async { Some(transfer.await) }, async {
+        Delay::new(timeout).await;
+        None
+    })
+    .await
+    .ok_or(UsbtmcErrors::Timeout)
+}
+
+/*
+* Sets the timeout of every USB transfer on this session. Defaults to 5 seconds
+*/
+pub fn set_timeout(usbtmc: &mut Usbtmc, timeout: Duration) {
+    usbtmc.timeout = timeout;
+}
+
+/*
+* Runs `f` with a different timeout and restores the session timeout afterwards
+*/
+pub fn with_timeout<T, F>(usbtmc: &mut Usbtmc, timeout: Duration, f: F) -> T
+where
+    F: FnOnce(&mut Usbtmc) -> T,
+{
+    let session_timeout = usbtmc.timeout;
+    usbtmc.timeout = timeout;
+
+    let result = f(usbtmc);
+
+    usbtmc.timeout = session_timeout;
+
+    result
+}
+
+pub(crate) fn notify_event(usbtmc: &Usbtmc, event: ConnectionEvent) {
+    log!("Connection event: {:?}\n", event);
+
+    if let Some(handler) = &usbtmc.event_handler {
+        (handler.lock().unwrap())(event);
+    }
+}
+
+fn device_disconnected(usbtmc: &Usbtmc) {
+    if !usbtmc.disconnected.swap(true, Ordering::SeqCst) {
+        notify_event(usbtmc, ConnectionEvent::Disconnected);
+    }
+}
+
+// a vanished device is reported as Disconnected instead of the generic transfer error
+fn transfer_error(usbtmc: &Usbtmc, error: TransferError, otherwise: UsbtmcErrors) -> UsbtmcErrors {
+    if error == TransferError::Disconnected {
+        device_disconnected(usbtmc);
+        UsbtmcErrors::Disconnected
+    } else {
+        otherwise
+    }
+}
+
+/*
+* Reconnects a session that lost its device if a reconnect policy is set, otherwise fails with
+* Disconnected
+*/
+async fn ensure_connected(usbtmc: &mut Usbtmc) -> Result<(), UsbtmcErrors> {
+    if !usbtmc.disconnected.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    if usbtmc.reconnect_policy.is_none() {
+        return Err(UsbtmcErrors::Disconnected);
+    }
+
+    boxed_reconnect(usbtmc).await
+}
+
+// the init commands go through bulk_out again, boxing breaks the recursive future type
+fn boxed_reconnect(
+    usbtmc: &mut Usbtmc,
+) -> Pin<Box<dyn Future<Output = Result<(), UsbtmcErrors>> + Send + '_>> {
+    Box::pin(crate::reconnect_async(usbtmc))
+}
+
+/*
+* Sets what happens after the device disappears. With a policy the next call re-enumerates the
+* device by VID, PID and serial number, claims the interface again and replays the init commands.
+* Without one every call fails with Disconnected until reconnect is called.
+*/
+pub fn set_reconnect_policy(usbtmc: &mut Usbtmc, policy: Option<ReconnectPolicy>) {
+    usbtmc.reconnect_policy = policy;
+}
+
+/*
+* Calls `handler` when the device disappears and after every reconnect attempt
+*/
+pub fn set_event_handler<F>(usbtmc: &mut Usbtmc, handler: F)
+where
+    F: FnMut(ConnectionEvent) + Send + 'static,
+{
+    usbtmc.event_handler = Some(Mutex::new(Box::new(handler)));
+}
+
+pub fn clear_event_handler(usbtmc: &mut Usbtmc) {
+    usbtmc.event_handler = None;
+}
+
+pub fn is_connected(usbtmc: &Usbtmc) -> bool {
+    !usbtmc.disconnected.load(Ordering::SeqCst)
+}
+
+async fn control_in_request(
+    usbtmc: &Usbtmc,
+    recipient: Recipient,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+) -> Result<Vec<u8>, UsbtmcErrors> {
+    let data = timeout_after(
+        usbtmc.interface.control_in(ControlIn {
+            control_type: ControlType::Class,
+            recipient,
+            request,
+            value,
+            index,
+            length,
+        }),
+        usbtmc.timeout,
+    )
+    .await?
+    .into_result()
+    .map_err(|error| transfer_error(usbtmc, error, UsbtmcErrors::ControlTransferError))?;
+
+    log!("control request {} ->: {:?}\n", request, data);
+
+    if data.len() < length as usize {
+        return Err(UsbtmcErrors::InvalidData);
+    }
+
+    Ok(data)
+}
+
+/*
+* USBTMC document section 4.2.1.8 and USB488 document section 4.2.2
+*/
+pub fn get_capabilities(usbtmc: &Usbtmc) -> Result<Capabilities, UsbtmcErrors> {
+    block_on(get_capabilities_async(usbtmc))
+}
+
+pub async fn get_capabilities_async(usbtmc: &Usbtmc) -> Result<Capabilities, UsbtmcErrors> {
+    let response = control_in_request(
+        usbtmc,
+        Recipient::Interface,
+        USBTMC_REQUEST_GET_CAPABILITIES,
+        0,
+        usbtmc.interface_number as u16,
+        0x18,
+    )
+    .await?;
+    check_status(response[0])?;
+
+    let bit = |byte: usize, bit: u8| response[byte] & (1 << bit) != 0;
+
+    Ok(Capabilities {
+        bcd_usbtmc: LittleEndian::read_u16(&response[2..4]),
+        indicator_pulse: bit(4, 2),
+        talk_only: bit(4, 1),
+        listen_only: bit(4, 0),
+        term_char: bit(5, 0),
+        bcd_usb488: LittleEndian::read_u16(&response[12..14]),
+        usb488_2: bit(14, 2),
+        ren_control: bit(14, 1),
+        trigger: bit(14, 0),
+        scpi: bit(15, 3),
+        sr1: bit(15, 2),
+        rl1: bit(15, 1),
+        dt1: bit(15, 0),
+    })
+}
+
+/*
+* USB488 document section 4.3.1. READ_STATUS_BYTE uses its own bTag in the range 2..127
+*/
+pub fn read_status_byte(usbtmc: &mut Usbtmc) -> Result<u8, UsbtmcErrors> {
+    let btag: u8 = if usbtmc.status_btag >= 127 {
+        2
+    } else {
+        usbtmc.status_btag + 1
+    };
+    usbtmc.status_btag = btag;
+
+    let response = block_on(control_in_request(
+        usbtmc,
+        Recipient::Interface,
+        USB488_REQUEST_READ_STATUS_BYTE,
+        btag as u16,
+        usbtmc.interface_number as u16,
+        3,
+    ))?;
+    check_status(response[0])?;
+
+    if response[1] != btag {
+        return Err(UsbtmcErrors::InvalidData);
+    }
+
+    if usbtmc.endpoint_interrupt_in_addr.is_none() {
+        return Ok(response[2]);
+    }
+
+    loop {
+        let notification = read_interrupt_notification(usbtmc)?;
+
+        if notification[0] == USB488_NOTIFY_STATUS_BYTE | btag {
+            return Ok(notification[1]);
+        }
+
+        if notification[0] == USB488_NOTIFY_SRQ {
+            usbtmc.pending_srq = Some(notification[1]);
+        }
+    }
+}
+
+// USB488 document Table 6 and Table 7: bNotify1 identifies the notification, bNotify2 is the status byte
+fn read_interrupt_notification(usbtmc: &mut Usbtmc) -> Result<Vec<u8>, UsbtmcErrors> {
+    let notification = match &usbtmc.srq_listener {
+        Some(listener) => match listener
+            .notifications
+            .lock()
+            .unwrap()
+            .recv_timeout(usbtmc.timeout)
+        {
+            Ok(notification) => notification,
+            Err(RecvTimeoutError::Timeout) => return Err(UsbtmcErrors::Timeout),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(UsbtmcErrors::InterruptInTransferError)
+            }
+        },
+        None => {
+            let endpoint_interrupt_in_addr = usbtmc
+                .endpoint_interrupt_in_addr
+                .ok_or(UsbtmcErrors::NoInterruptEndpoint)?;
+            let request_buffer = RequestBuffer::new(usbtmc.endpoint_interrupt_in_max_packet_size);
+
+            block_on(timeout_after(
+                usbtmc
+                    .interface
+                    .interrupt_in(endpoint_interrupt_in_addr, request_buffer),
+                usbtmc.timeout,
+            ))?
+            .into_result()
+            .map_err(|error| {
+                transfer_error(usbtmc, error, UsbtmcErrors::InterruptInTransferError)
+            })?
+        }
+    };
+
+    log!("interrupt in ->: {:?}\n", notification);
+
+    if notification.len() < 2 {
+        return Err(UsbtmcErrors::InvalidData);
+    }
+
+    Ok(notification)
+}
+
+/*
+* USB488 document section 3.4.1. Blocks until the device requests service and returns the status byte
+*/
+pub fn wait_for_srq(usbtmc: &mut Usbtmc) -> Result<u8, UsbtmcErrors> {
+    if usbtmc.srq_listener.is_some() {
+        return Err(UsbtmcErrors::SrqHandlerInstalled);
+    }
+
+    if let Some(stb) = usbtmc.pending_srq.take() {
+        return Ok(stb);
+    }
+
+    loop {
+        let notification = read_interrupt_notification(usbtmc)?;
+
+        if notification[0] == USB488_NOTIFY_SRQ {
+            return Ok(notification[1]);
+        }
+    }
+}
+
+/*
+* Calls `handler` with the status byte of every SRQ from a background thread, until
+* clear_srq_handler is called or the Usbtmc is dropped
+*/
+pub fn set_srq_handler<F>(usbtmc: &mut Usbtmc, mut handler: F) -> Result<(), UsbtmcErrors>
+where
+    F: FnMut(u8) + Send + 'static,
+{
+    let endpoint_interrupt_in_addr = usbtmc
+        .endpoint_interrupt_in_addr
+        .ok_or(UsbtmcErrors::NoInterruptEndpoint)?;
+    let max_packet_size = usbtmc.endpoint_interrupt_in_max_packet_size;
+
+    clear_srq_handler(usbtmc);
+
+    if let Some(stb) = usbtmc.pending_srq.take() {
+        handler(stb);
+    }
+
+    let stop = Arc::new(Mutex::new(StopSignal::default()));
+    let (sender, notifications) = channel();
+
+    let interface = usbtmc.interface.clone();
+    let thread_stop = stop.clone();
+
+    thread::spawn(move || loop {
+        let transfer = interface.interrupt_in(
+            endpoint_interrupt_in_addr,
+            RequestBuffer::new(max_packet_size),
+        );
+        let stopped = future::poll_fn(|cx| {
+            let mut stop = thread_stop.lock().unwrap();
+            if stop.stopped {
+                Poll::Ready(None)
+            } else {
+                stop.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        });
+
+        let notification = match block_on(future::or(async { Some(transfer.await) }, stopped)) {
+            Some(completion) => match completion.into_result() {
+                Ok(notification) => notification,
+                Err(_) => break,
+            },
+            None => break,
+        };
+
+        if notification.len() >= 2 && notification[0] == USB488_NOTIFY_SRQ {
+            handler(notification[1]);
+        } else if sender.send(notification).is_err() {
+            break;
+        }
+    });
+
+    usbtmc.srq_listener = Some(SrqListener {
+        stop,
+        notifications: Mutex::new(notifications),
+    });
+
+    Ok(())
+}
+
+pub fn clear_srq_handler(usbtmc: &mut Usbtmc) {
+    usbtmc.srq_listener = None;
+}
+
+/*
+* USB488 document section 4.3.2, 4.3.3 and 4.3.4
+*/
+fn remote_local_request(usbtmc: &Usbtmc, request: u8, value: u16) -> Result<(), UsbtmcErrors> {
+    if !usbtmc.capabilities.ren_control {
+        return Err(UsbtmcErrors::NotSupported);
+    }
+
+    let response = block_on(control_in_request(
+        usbtmc,
+        Recipient::Interface,
+        request,
+        value,
+        usbtmc.interface_number as u16,
+        1,
+    ))?;
+    check_status(response[0])?;
+
+    Ok(())
+}
+
+pub fn ren_control(usbtmc: &Usbtmc, enable: bool) -> Result<(), UsbtmcErrors> {
+    remote_local_request(usbtmc, USB488_REQUEST_REN_CONTROL, enable as u16)
+}
+
+pub fn go_to_local(usbtmc: &Usbtmc) -> Result<(), UsbtmcErrors> {
+    remote_local_request(usbtmc, USB488_REQUEST_GO_TO_LOCAL, 0)
+}
+
+pub fn local_lockout(usbtmc: &Usbtmc) -> Result<(), UsbtmcErrors> {
+    remote_local_request(usbtmc, USB488_REQUEST_LOCAL_LOCKOUT, 0)
+}
+
+/*
+* USBTMC document Table 5
+*/
+pub fn pack_vendor_specific_out_header(transfer_size: usize, btag: u8) -> Vec<u8> {
+    let mut header = pack_bulk_out_header(USBTMC_MSGID_VENDOR_SPECIFIC_OUT, btag);
+
+    let mut total_transfer_size: Vec<u8> = little_write_u32(transfer_size as u32, 4);
+
+    header.append(&mut total_transfer_size);
+    header.append(&mut vec![0x00; 4]);
+
+    header
+}
+
+/*
+* USBTMC document Table 6
+*/
+pub fn pack_vendor_specific_in_header(transfer_size: usize, btag: u8) -> Vec<u8> {
+    let mut header = pack_bulk_out_header(USBTMC_MSGID_VENDOR_SPECIFIC_IN, btag);
+
+    let mut total_transfer_size: Vec<u8> = little_write_u32(transfer_size as u32, 4);
+
+    header.append(&mut total_transfer_size);
+    header.append(&mut vec![0x00; 4]);
+
+    header
+}
+
+/*
+* USB488 document Table 2
+*/
+fn pack_trigger_header(btag: u8) -> Vec<u8> {
+    let mut header = pack_bulk_out_header(USB488_MSGID_TRIGGER, btag);
+
+    header.append(&mut vec![0x00; 8]);
+
+    header
+}
+
+/*
+* USB488 document section 3.2.1.1. Equivalent to a GPIB Group Execute Trigger
+*/
+pub fn trigger(usbtmc: &mut Usbtmc) -> Result<(), UsbtmcErrors> {
+    if !usbtmc.capabilities.trigger {
+        return Err(UsbtmcErrors::NotSupported);
+    }
+
+    let btag = next_btag(usbtmc);
+    let req = pack_trigger_header(btag);
+
+    block_on(bulk_out(usbtmc, req))
+}
+
+/*
+* Sends one Bulk-OUT transfer. If it times out the transfer is aborted so the session stays usable
+*/
+async fn bulk_out(usbtmc: &mut Usbtmc, req: Vec<u8>) -> Result<(), UsbtmcErrors> {
+    ensure_connected(usbtmc).await?;
+    usbtmc.last_btag_out = req[1];
+
+    let transfer = usbtmc.interface.bulk_out(usbtmc.endpoint_out_addr, req);
+    let ok = match timeout_after(transfer, usbtmc.timeout).await {
+        Ok(completion) => completion
+            .into_result()
+            .map_err(|error| transfer_error(usbtmc, error, UsbtmcErrors::BulkOutTransferError))?,
+        Err(error) => {
+            log!("Bulk out transfer timed out. Aborting.\n");
+            let _ = abort_bulk_out_async(usbtmc).await;
+            return Err(error);
+        }
+    };
+
+    log!("ok->: {:?}\n", ok);
+
+    Ok(())
+}
+
+pub(crate) fn is_query(data: &[u8]) -> bool {
     // Define the byte you are looking for
     let question_mark = b'?';
 
@@ -111,75 +823,433 @@ fn is_query(data: &[u8]) -> bool {
     data.contains(&question_mark)
 }
 
-fn read_data_transfer(
+/*
+* Size and number of the Bulk-IN transfers kept queued on the endpoint while reading a response.
+* Faster buses need more data in flight to keep the endpoint busy between completions.
+*/
+fn bulk_in_queue_params(usbtmc: &Usbtmc) -> (usize, usize) {
+    let packet_size = usbtmc.endpoint_in_max_packet_size;
+
+    match usbtmc.speed {
+        Some(Speed::Low) | Some(Speed::Full) => (64 * packet_size, 2),
+        Some(Speed::High) => (256 * packet_size, 4),
+        Some(Speed::Super) | Some(Speed::SuperPlus) => (256 * packet_size, 8),
+        _ => (256 * packet_size, 4),
+    }
+}
+
+async fn read_data_transfer(
     usbtmc: &mut Usbtmc,
-    big_buffer: &mut Vec<u8>,
-) -> Result<usize, UsbtmcErrors> {
-    let recv_buffer_size = usbtmc.endpoint_in_max_packet_size * 1024;
+    recv_buffer_size: usize,
+) -> Result<Vec<u8>, UsbtmcErrors> {
     let request_buffer = RequestBuffer::new(recv_buffer_size);
-    let okr_result = block_on(
+    let okr_result = timeout_after(
         usbtmc
             .interface
             .bulk_in(usbtmc.endpoint_in_addr, request_buffer),
+        usbtmc.timeout,
     )
+    .await?
     .into_result();
 
-    let okr = okr_result.map_err(|_| UsbtmcErrors::BulkInTransferError)?;
+    let okr = okr_result
+        .map_err(|error| transfer_error(usbtmc, error, UsbtmcErrors::BulkInTransferError))?;
 
     log!("okr->: ");
 
     #[cfg(debug_assertions)]
     print_array_partial(okr.to_vec());
 
-    big_buffer.extend_from_slice(&okr);
+    log!("usb packet size: {}\n", okr.len());
 
-    let usb_packet_size = okr.len();
-    log!("usb packet size: {}\n", usb_packet_size);
+    Ok(okr)
+}
 
-    Ok(usb_packet_size)
+async fn read_data<F>(
+    usbtmc: &mut Usbtmc,
+    use_term_char: bool,
+    on_payload: &mut F,
+) -> Result<bool, UsbtmcErrors>
+where
+    F: FnMut(&[u8]) -> Result<(), UsbtmcErrors>,
+{
+    // let the device send large responses in one message so the queued Bulk-IN transfers keep
+    // the endpoint busy instead of waiting for a new request after every transfer
+    let (transfer_size, queue_depth) = bulk_in_queue_params(usbtmc);
+    let max_transfer_size: usize =
+        USBTMC_QUEUED_TRANSFERS_PER_MESSAGE * queue_depth * transfer_size;
+
+    // the device only honours TermChar if it says so in GET_CAPABILITIES, otherwise the host
+    // checks the termination character in send_command_raw_binary
+    let term_char = if use_term_char && usbtmc.capabilities.term_char {
+        Some(usbtmc.term_char)
+    } else {
+        None
+    };
+
+    let btag = next_btag(usbtmc);
+    let send = pack_dev_dep_msg_in_header(max_transfer_size, term_char, btag);
+    let header = read_message(usbtmc, send, on_payload).await?;
+
+    // a transfer that ended on TermChar is the end of the response even without EOM
+    Ok(header.eom || header.term_char)
 }
 
-fn read_data(usbtmc: &mut Usbtmc, big_big_buffer: &mut Vec<u8>) -> Result<bool, UsbtmcErrors> {
-    let max_transfer_size: usize = 1024 * usbtmc.endpoint_in_max_packet_size;
+/*
+* Sends a Bulk-OUT request message and passes the Bulk-IN response payload to `on_payload` as each
+* transfer arrives. Returns the header of the response.
+*/
+async fn read_message<F>(
+    usbtmc: &mut Usbtmc,
+    send: Vec<u8>,
+    on_payload: &mut F,
+) -> Result<BulkInHeader, UsbtmcErrors>
+where
+    F: FnMut(&[u8]) -> Result<(), UsbtmcErrors>,
+{
+    let msgid = send[0];
+    let btag = send[1];
+    usbtmc.last_btag_in = btag;
+
+    bulk_out(usbtmc, send).await?;
+
+    let result = read_response(usbtmc, msgid, btag, on_payload).await;
+
+    if matches!(result, Err(UsbtmcErrors::Timeout)) {
+        log!("Bulk in transfer timed out. Aborting.\n");
+        let _ = abort_bulk_in_async(usbtmc).await;
+    }
+
+    result
+}
+
+/*
+* USBTMC document Table 9 and Table 11, the fields of a DEV_DEP_MSG_IN or VENDOR_SPECIFIC_IN
+* response header
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BulkInHeader {
+    pub transfer_size: usize,
+    pub eom: bool,
+    // the transfer ended on the TermChar of the request
+    pub term_char: bool,
+}
+
+/*
+* Checks the header at the start of the first transfer of a response against the MsgID and bTag of
+* the request. Returns it with the payload bytes in `transfer`, alignment bytes after TransferSize
+* are left out.
+*/
+pub fn parse_bulk_in_header(
+    transfer: &[u8],
+    msgid: u8,
+    btag: u8,
+) -> Result<(BulkInHeader, &[u8]), UsbtmcErrors> {
+    if transfer.len() < 12 {
+        return Err(UsbtmcErrors::InvalidData);
+    }
+
+    // USBTMC document Table 8 and Table 10: the response echoes the MsgID and bTag of the request
+    if transfer[0] != msgid {
+        return Err(UsbtmcErrors::MsgIdMismatch(transfer[0]));
+    }
+    if transfer[1] != btag {
+        return Err(UsbtmcErrors::BtagMismatch(transfer[1]));
+    }
+    if transfer[2] != !btag {
+        return Err(UsbtmcErrors::BtagInverseMismatch);
+    }
+
+    let header = BulkInHeader {
+        transfer_size: LittleEndian::read_u32(&transfer[4..8]) as usize,
+        // USBTMC document Table 9: D0 is EOM, D1 is set when the transfer ended on TermChar
+        eom: transfer[8] & 0x01 != 0,
+        term_char: transfer[8] & 0x02 != 0,
+    };
+
+    let payload_end = transfer.len().min(12 + header.transfer_size);
+
+    Ok((header, &transfer[12..payload_end]))
+}
+
+async fn read_response<F>(
+    usbtmc: &mut Usbtmc,
+    msgid: u8,
+    btag: u8,
+    on_payload: &mut F,
+) -> Result<BulkInHeader, UsbtmcErrors>
+where
+    F: FnMut(&[u8]) -> Result<(), UsbtmcErrors>,
+{
+    let packet_size: usize = usbtmc.endpoint_in_max_packet_size;
     let mut big_buffer: Vec<u8> = Vec::new();
 
-    let buffer_size = max_transfer_size;
+    // Read a single packet first so a response shorter than the buffer can never leave the
+    // transfer waiting for more data. A zero-length packet here is left over from the previous
+    // response and is skipped.
+    let mut usb_packet_recv_size: usize = 0;
+    while big_buffer.len() < 12 {
+        let packet = read_data_transfer(usbtmc, packet_size).await?;
+        usb_packet_recv_size = packet.len();
+        big_buffer.extend_from_slice(&packet);
+
+        if usb_packet_recv_size < packet_size && !big_buffer.is_empty() && big_buffer.len() < 12 {
+            log!("Short packet before the end of the header.\n");
+            return Err(UsbtmcErrors::InvalidData);
+        }
+    }
+    let mut short_packet: bool = usb_packet_recv_size < packet_size;
+
+    let (header, payload) = parse_bulk_in_header(&big_buffer, msgid, btag)?;
+    let payload_size = header.transfer_size;
+    log!("Response header: {:?}\n", header);
+
+    let mut received = payload.len();
+    on_payload(payload)?;
+
+    // TransferSize decides how much is left to read, the device ends the transfer with a short
+    // packet if it has less to send. Only whole packets up to the end of this response are ever
+    // queued so no transfer can pick up data belonging to the next response.
+    let (transfer_size, queue_depth) = bulk_in_queue_params(usbtmc);
+    let mut queue = usbtmc.interface.bulk_in_queue(usbtmc.endpoint_in_addr);
+    let mut requested: VecDeque<usize> = VecDeque::new();
+    let mut spare_buffers: Vec<Vec<u8>> = Vec::new();
+    let mut to_submit = (payload_size - received).div_ceil(packet_size) * packet_size;
+
+    while received < payload_size {
+        if short_packet {
+            log!("Short packet before the end of the transfer.\n");
+            return Err(UsbtmcErrors::InvalidData);
+        }
 
-    let send = pack_dev_dep_msg_in_header(max_transfer_size, 0);
-    let ok2_results =
-        block_on(usbtmc.interface.bulk_out(usbtmc.endpoint_out_addr, send)).into_result();
+        while queue.pending() < queue_depth && to_submit > 0 {
+            let recv_buffer_size = to_submit.min(transfer_size);
+            let request_buffer = match spare_buffers.pop() {
+                Some(buffer) => RequestBuffer::reuse(buffer, recv_buffer_size),
+                None => RequestBuffer::new(recv_buffer_size),
+            };
+            queue.submit(request_buffer);
+            requested.push_back(recv_buffer_size);
+            to_submit -= recv_buffer_size;
+        }
 
-    let ok2 = ok2_results.map_err(|_| UsbtmcErrors::BulkOutTransferError)?;
+        log!(
+            "Reading {} more bytes, {} transfers queued.\n",
+            payload_size - received,
+            queue.pending()
+        );
+        let completion = timeout_after(queue.next_complete(), usbtmc.timeout).await?;
+        let recv_buffer_size = requested.pop_front().unwrap_or(0);
+        completion
+            .status
+            .map_err(|error| transfer_error(usbtmc, error, UsbtmcErrors::BulkInTransferError))?;
+
+        let packet = completion.data;
+        short_packet = packet.len() < recv_buffer_size;
+
+        let usable = packet.len().min(payload_size - received);
+        on_payload(&packet[..usable])?;
+        received += usable;
+        spare_buffers.push(packet);
+    }
 
-    log!("ok2->: {:?}\n", ok2);
+    Ok(header)
+}
 
-    let mut usb_packet_recv_size = read_data_transfer(usbtmc, &mut big_buffer)?;
+/*
+* USBTMC document section 4.2.1.2 and 4.2.1.3
+*/
+pub fn abort_bulk_out(usbtmc: &mut Usbtmc) -> Result<(), UsbtmcErrors> {
+    block_on(abort_bulk_out_async(usbtmc))
+}
 
-    // Separate the bytes
-    let (header, _): (&[u8], &[u8]) = big_buffer.split_at(12);
+pub async fn abort_bulk_out_async(usbtmc: &mut Usbtmc) -> Result<(), UsbtmcErrors> {
+    let endpoint = usbtmc.endpoint_out_addr as u16;
+
+    log!(
+        "Aborting bulk out transfer with btag: {}\n",
+        usbtmc.last_btag_out
+    );
+
+    let response = control_in_request(
+        usbtmc,
+        Recipient::Endpoint,
+        USBTMC_REQUEST_INITIATE_ABORT_BULK_OUT,
+        usbtmc.last_btag_out as u16,
+        endpoint,
+        2,
+    )
+    .await?;
+    check_status(response[0])?;
 
-    // Convert the first part to hexadecimal values and print
-    log!("Header Hex values: ");
-    #[cfg(debug_assertions)]
-    for byte in header {
-        log!("{:02x} ", byte);
+    let start = Instant::now();
+    loop {
+        if start.elapsed() > usbtmc.timeout {
+            return Err(UsbtmcErrors::Timeout);
+        }
+
+        let response = control_in_request(
+            usbtmc,
+            Recipient::Endpoint,
+            USBTMC_REQUEST_CHECK_ABORT_BULK_OUT_STATUS,
+            0,
+            endpoint,
+            8,
+        )
+        .await?;
+
+        if poll_check_status(&response)? == StatusPoll::Done {
+            log!(
+                "bytes received by device: {}\n",
+                LittleEndian::read_u32(&response[4..8])
+            );
+            break;
+        }
+
+        Delay::new(USBTMC_CHECK_STATUS_INTERVAL).await;
     }
-    log!("\n");
 
-    let payload_size = LittleEndian::read_u32(&header[4..8]) as usize;
-    log!("Payload size in header: {}\n", payload_size);
+    usbtmc
+        .interface
+        .clear_halt(usbtmc.endpoint_out_addr)
+        .map_err(|_| UsbtmcErrors::ControlTransferError)
+}
+
+/*
+* USBTMC document section 4.2.1.4 and 4.2.1.5
+*/
+pub fn abort_bulk_in(usbtmc: &mut Usbtmc) -> Result<(), UsbtmcErrors> {
+    block_on(abort_bulk_in_async(usbtmc))
+}
+
+pub async fn abort_bulk_in_async(usbtmc: &mut Usbtmc) -> Result<(), UsbtmcErrors> {
+    let endpoint = usbtmc.endpoint_in_addr as u16;
+
+    log!(
+        "Aborting bulk in transfer with btag: {}\n",
+        usbtmc.last_btag_in
+    );
+
+    let response = control_in_request(
+        usbtmc,
+        Recipient::Endpoint,
+        USBTMC_REQUEST_INITIATE_ABORT_BULK_IN,
+        usbtmc.last_btag_in as u16,
+        endpoint,
+        2,
+    )
+    .await?;
+    check_status(response[0])?;
+
+    // the device terminates the aborted transfer with a short packet
+    drain_bulk_in(usbtmc).await?;
+
+    let start = Instant::now();
+    loop {
+        if start.elapsed() > usbtmc.timeout {
+            return Err(UsbtmcErrors::Timeout);
+        }
+
+        let response = control_in_request(
+            usbtmc,
+            Recipient::Endpoint,
+            USBTMC_REQUEST_CHECK_ABORT_BULK_IN_STATUS,
+            0,
+            endpoint,
+            8,
+        )
+        .await?;
+
+        match poll_check_status(&response)? {
+            StatusPoll::Done => {
+                log!(
+                    "bytes sent by device: {}\n",
+                    LittleEndian::read_u32(&response[4..8])
+                );
+                break;
+            }
+            StatusPoll::DrainBulkIn => drain_bulk_in(usbtmc).await?,
+            StatusPoll::Wait => Delay::new(USBTMC_CHECK_STATUS_INTERVAL).await,
+        }
+    }
+
+    Ok(())
+}
+
+/*
+* USBTMC document section 4.2.1.6 and 4.2.1.7
+*/
+pub fn clear(usbtmc: &mut Usbtmc) -> Result<(), UsbtmcErrors> {
+    block_on(clear_async(usbtmc))
+}
+
+pub async fn clear_async(usbtmc: &mut Usbtmc) -> Result<(), UsbtmcErrors> {
+    let interface_number = usbtmc.interface_number as u16;
+
+    log!("Clearing device\n");
 
-    let eom: bool = header[8] == 0x01;
-    log!("EOM: {}\n", eom);
+    let response = control_in_request(
+        usbtmc,
+        Recipient::Interface,
+        USBTMC_REQUEST_INITIATE_CLEAR,
+        0,
+        interface_number,
+        1,
+    )
+    .await?;
+    check_status(response[0])?;
 
-    while usb_packet_recv_size == buffer_size {
-        log!("Packet size equals buffer size. Reading more data.\n");
-        usb_packet_recv_size = read_data_transfer(usbtmc, &mut big_buffer)?;
+    let start = Instant::now();
+    loop {
+        if start.elapsed() > usbtmc.timeout {
+            return Err(UsbtmcErrors::Timeout);
+        }
+
+        let response = control_in_request(
+            usbtmc,
+            Recipient::Interface,
+            USBTMC_REQUEST_CHECK_CLEAR_STATUS,
+            0,
+            interface_number,
+            2,
+        )
+        .await?;
+
+        match poll_check_status(&response)? {
+            StatusPoll::Done => break,
+            StatusPoll::DrainBulkIn => drain_bulk_in(usbtmc).await?,
+            StatusPoll::Wait => Delay::new(USBTMC_CHECK_STATUS_INTERVAL).await,
+        }
     }
 
-    big_big_buffer.extend_from_slice(&big_buffer[12..]);
+    usbtmc
+        .interface
+        .clear_halt(usbtmc.endpoint_out_addr)
+        .map_err(|_| UsbtmcErrors::ControlTransferError)
+}
 
-    Ok(eom)
+async fn drain_bulk_in(usbtmc: &mut Usbtmc) -> Result<(), UsbtmcErrors> {
+    let buffer_size = usbtmc.endpoint_in_max_packet_size * 1024;
+
+    while read_data_transfer(usbtmc, buffer_size).await?.len() == buffer_size {}
+
+    Ok(())
+}
+
+/*
+* Sets the character that terminates responses. Defaults to '\n'
+*/
+pub fn set_term_char(usbtmc: &mut Usbtmc, term_char: u8) {
+    usbtmc.term_char = term_char;
+}
+
+/*
+* Lets a device with the TermChar capability end a response on the termination character, like
+* VI_ATTR_TERMCHAR_EN. Defaults to off, the host then only checks the last byte. Binary block reads
+* never ask for it because the payload may contain the character.
+*/
+pub fn set_term_char_enabled(usbtmc: &mut Usbtmc, enabled: bool) {
+    usbtmc.term_char_enabled = enabled;
 }
 
 pub fn write_str(usbtmc: &mut Usbtmc, command_data: &str) -> Result<(), UsbtmcErrors> {
@@ -191,49 +1261,104 @@ pub fn write_str(usbtmc: &mut Usbtmc, command_data: &str) -> Result<(), UsbtmcEr
 }
 
 pub fn write_binary(usbtmc: &mut Usbtmc, in_data: &[u8]) -> Result<(), UsbtmcErrors> {
-    let interface = &mut usbtmc.interface;
+    block_on(write_binary_async(usbtmc, in_data))
+}
 
+pub async fn write_binary_async(usbtmc: &mut Usbtmc, in_data: &[u8]) -> Result<(), UsbtmcErrors> {
+    write_chunks(usbtmc, in_data, pack_dev_dep_msg_out_header).await
+}
+
+/*
+* Splits `in_data` into Bulk-OUT transfers of at most 1024 packets, each with the header from
+* `pack_header(transfer_size, eom, btag)`
+*/
+async fn write_chunks<F>(
+    usbtmc: &mut Usbtmc,
+    in_data: &[u8],
+    pack_header: F,
+) -> Result<(), UsbtmcErrors>
+where
+    F: Fn(usize, bool, u8) -> Vec<u8>,
+{
     let mut size: usize = in_data.len();
     let max_data_size: usize = 1024 * usbtmc.endpoint_out_max_packet_size;
 
     let mut data = in_data;
 
-    let mut btag: u8 = 0x00;
-
     while size > max_data_size {
-        let send = pack_dev_dep_msg_out_header(max_data_size, false, btag);
+        let btag = next_btag(usbtmc);
+        let send = pack_header(max_data_size, false, btag);
         let mut b: Vec<u8> = data[0..max_data_size].to_vec();
         let mut req = send;
         req.append(&mut b);
         req.append(&mut vec![0x00; (4 - (max_data_size % 4)) % 4]);
 
-        let ok_results: Result<nusb::transfer::ResponseBuffer, TransferError> =
-            block_on(interface.bulk_out(usbtmc.endpoint_out_addr, req)).into_result();
-
-        let ok = ok_results.map_err(|_| UsbtmcErrors::BulkOutTransferError)?;
-
-        log!("ok->: {:?}\n", ok);
+        bulk_out(usbtmc, req).await?;
 
         size -= max_data_size;
 
         data = &data[max_data_size..];
-
-        btag = (btag % 255) + 1;
     }
 
-    let mut req = pack_dev_dep_msg_out_header(size, true, btag);
+    let btag = next_btag(usbtmc);
+    let mut req = pack_header(size, true, btag);
     let mut b: Vec<u8> = data.to_vec();
     req.append(&mut b);
     req.append(&mut vec![0x00; (4 - (size % 4)) % 4]);
 
-    let ok_results: Result<nusb::transfer::ResponseBuffer, TransferError> =
-        block_on(interface.bulk_out(usbtmc.endpoint_out_addr, req)).into_result();
+    bulk_out(usbtmc, req).await
+}
 
-    let ok = ok_results.map_err(|_| UsbtmcErrors::BulkOutTransferError)?;
+/*
+* USBTMC document section 3.2.1.2. Vendor specific messages have no EOM, the payload is chunked
+* the same way as write_binary
+*/
+pub fn write_vendor_specific(usbtmc: &mut Usbtmc, data: &[u8]) -> Result<(), UsbtmcErrors> {
+    block_on(write_vendor_specific_async(usbtmc, data))
+}
 
-    log!("ok->: {:?}\n", ok);
+pub async fn write_vendor_specific_async(
+    usbtmc: &mut Usbtmc,
+    data: &[u8],
+) -> Result<(), UsbtmcErrors> {
+    write_chunks(usbtmc, data, |size, _, btag| {
+        pack_vendor_specific_out_header(size, btag)
+    })
+    .await
+}
 
-    Ok(())
+/*
+* USBTMC document section 3.3.1.2. Requests VENDOR_SPECIFIC_IN transfers until `length` bytes
+* have been received or the device sends less than requested
+*/
+pub fn read_vendor_specific(usbtmc: &mut Usbtmc, length: usize) -> Result<Vec<u8>, UsbtmcErrors> {
+    block_on(read_vendor_specific_async(usbtmc, length))
+}
+
+pub async fn read_vendor_specific_async(
+    usbtmc: &mut Usbtmc,
+    length: usize,
+) -> Result<Vec<u8>, UsbtmcErrors> {
+    let max_transfer_size: usize = 1024 * usbtmc.endpoint_in_max_packet_size - 12;
+    let mut big_big_buffer: Vec<u8> = Vec::new();
+
+    while big_big_buffer.len() < length {
+        let requested = (length - big_big_buffer.len()).min(max_transfer_size);
+        let btag = next_btag(usbtmc);
+        let send = pack_vendor_specific_in_header(requested, btag);
+
+        let mut on_payload = |payload: &[u8]| {
+            big_big_buffer.extend_from_slice(payload);
+            Ok(())
+        };
+        let header = read_message(usbtmc, send, &mut on_payload).await?;
+
+        if header.transfer_size < requested {
+            break;
+        }
+    }
+
+    Ok(big_big_buffer)
 }
 
 pub fn send_command_raw_binary(
@@ -241,23 +1366,31 @@ pub fn send_command_raw_binary(
     data: &[u8],
     query: bool,
 ) -> Result<Vec<u8>, UsbtmcErrors> {
-    write_binary(usbtmc, data)?;
+    block_on(send_command_raw_binary_async(usbtmc, data, query))
+}
+
+pub async fn send_command_raw_binary_async(
+    usbtmc: &mut Usbtmc,
+    data: &[u8],
+    query: bool,
+) -> Result<Vec<u8>, UsbtmcErrors> {
+    write_binary_async(usbtmc, data).await?;
 
     let mut big_big_buffer: Vec<u8> = Vec::new();
 
     if query {
         log!("query detected\n");
-        let mut eom: bool = read_data(usbtmc, &mut big_big_buffer)?;
-
-        while !eom {
-            log!("eom is false. Reading more data.\n");
-            eom = read_data(usbtmc, &mut big_big_buffer)?;
-        }
+        let use_term_char = usbtmc.term_char_enabled;
+        read_until_eom(usbtmc, use_term_char, &mut |payload: &[u8]| {
+            big_big_buffer.extend_from_slice(payload);
+            Ok(())
+        })
+        .await?;
         log!(
             "transfer complete. total payload size: {}\n",
             big_big_buffer.len()
         );
-        let term_recv: bool = big_big_buffer[big_big_buffer.len() - 1] == 0x0A; //=10
+        let term_recv: bool = big_big_buffer.last() == Some(&usbtmc.term_char);
         log!("term_recv: {}\n", term_recv);
 
         if !term_recv {
@@ -282,23 +1415,57 @@ pub fn send_command_raw_binary(
     Ok(big_big_buffer)
 }
 
-pub(crate) fn send_command_raw(
+async fn read_until_eom<F>(
     usbtmc: &mut Usbtmc,
-    command: &str,
-) -> Result<Vec<u8>, UsbtmcErrors> {
-    let command_with_newline = command.to_owned() + "\n";
-    let command_data = command_with_newline.as_bytes();
-
-    log!("Sending command: {:?}\n", command);
-    let query = is_query(command_data);
+    use_term_char: bool,
+    on_payload: &mut F,
+) -> Result<(), UsbtmcErrors>
+where
+    F: FnMut(&[u8]) -> Result<(), UsbtmcErrors>,
+{
+    let mut done: bool = read_data(usbtmc, use_term_char, on_payload).await?;
+
+    while !done {
+        log!("eom is false. Reading more data.\n");
+        done = read_data(usbtmc, use_term_char, on_payload).await?;
+    }
 
-    send_command_raw_binary(usbtmc, command_data, query)
+    Ok(())
 }
 
-pub(crate) fn send_command(usbtmc: &mut Usbtmc, command: &str) -> Result<String, UsbtmcErrors> {
-    let data: Vec<u8> = send_command_raw(usbtmc, command)?;
+impl Transport for Usbtmc {
+    async fn write_message(&mut self, data: &[u8]) -> Result<(), UsbtmcErrors> {
+        write_binary_async(self, data).await
+    }
+
+    async fn read_message<F>(&mut self, on_payload: &mut F) -> Result<(), UsbtmcErrors>
+    where
+        F: FnMut(&[u8]) -> Result<(), UsbtmcErrors> + Send,
+    {
+        let use_term_char = self.term_char_enabled;
+        read_until_eom(self, use_term_char, on_payload).await
+    }
+
+    async fn read_block_message<F>(&mut self, on_payload: &mut F) -> Result<(), UsbtmcErrors>
+    where
+        F: FnMut(&[u8]) -> Result<(), UsbtmcErrors> + Send,
+    {
+        read_until_eom(self, false, on_payload).await
+    }
+
+    async fn clear(&mut self) -> Result<(), UsbtmcErrors> {
+        clear_async(self).await
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
 
-    let ascii_string: String = data.iter().map(|&b| b as char).collect();
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
 
-    Ok(ascii_string)
+    fn term_char(&self) -> u8 {
+        self.term_char
+    }
 }
@@ -0,0 +1,693 @@
+/*
+* VXI-11 over ONC RPC (RFC 5531) on TCP. The core channel carries device_write, device_read and the
+* other device operations, the abort channel only device_abort.
+*
+* VXI-11 specification revision 1.0, section B.6 lists the RPC interface
+*/
+
+use crate::tcp::{connect, io_error, resolve};
+use crate::transport::Transport;
+use crate::usbtmc::{timeout_after, UsbtmcErrors};
+use async_io::Async;
+use futures_lite::future::block_on;
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+const PORTMAPPER_PORT: u16 = 111;
+const PORTMAPPER_PROGRAM: u32 = 100000;
+const PORTMAPPER_VERSION: u32 = 2;
+const PORTMAPPER_PROC_GETPORT: u32 = 3;
+const IPPROTO_TCP: u32 = 6;
+
+const DEVICE_CORE_PROGRAM: u32 = 0x0607AF;
+const DEVICE_CORE_VERSION: u32 = 1;
+const DEVICE_ASYNC_PROGRAM: u32 = 0x0607B0;
+const DEVICE_ASYNC_VERSION: u32 = 1;
+
+const DEVICE_ABORT: u32 = 1;
+const CREATE_LINK: u32 = 10;
+const DEVICE_WRITE: u32 = 11;
+const DEVICE_READ: u32 = 12;
+const DEVICE_READSTB: u32 = 13;
+const DEVICE_TRIGGER: u32 = 14;
+const DEVICE_CLEAR: u32 = 15;
+const DEVICE_REMOTE: u32 = 16;
+const DEVICE_LOCAL: u32 = 17;
+const DEVICE_LOCK: u32 = 18;
+const DEVICE_UNLOCK: u32 = 19;
+const DESTROY_LINK: u32 = 23;
+
+/*
+* VXI-11 section B.5.3 Device_Flags and B.6.12 Device_ReadResp reason
+*/
+const FLAG_WAITLOCK: u32 = 0x01;
+const FLAG_END: u32 = 0x08;
+const FLAG_TERMCHRSET: u32 = 0x80;
+
+const REASON_CHR: u32 = 0x02;
+const REASON_END: u32 = 0x04;
+
+/*
+* VXI-11 section B.5.4 Device_ErrorCode
+*/
+const ERROR_IO_TIMEOUT: u32 = 15;
+
+const RPC_CALL: u32 = 0;
+const RPC_REPLY: u32 = 1;
+const RPC_VERSION: u32 = 2;
+const RPC_MSG_ACCEPTED: u32 = 0;
+const RPC_SUCCESS: u32 = 0;
+const RPC_LAST_FRAGMENT: u32 = 0x8000_0000;
+
+const VXI11_DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const VXI11_READ_SIZE: u32 = 1024 * 1024;
+// the device gives up after io_timeout and lock_timeout, the client waits a little longer for its
+// reply
+const VXI11_RPC_MARGIN: Duration = Duration::from_secs(1);
+
+pub struct Vxi11 {
+    stream: Async<TcpStream>,
+    core_address: SocketAddr,
+    device: String,
+    // a call timed out, the rest of its reply may still arrive on `stream`
+    out_of_frame: bool,
+    xid: u32,
+    link_id: u32,
+    abort_address: SocketAddr,
+    max_recv_size: u32,
+    timeout: Duration,
+    lock_timeout: Duration,
+    term_char: u8,
+    term_char_enabled: bool,
+}
+
+/*
+* Sends device_abort on the abort channel. It can be used from another thread or task while a call
+* on the core channel is waiting. A handle taken before a timeout belongs to the old link, take a
+* new one afterwards.
+*/
+#[derive(Debug, Clone)]
+pub struct AbortHandle {
+    address: SocketAddr,
+    link_id: u32,
+}
+
+macro_rules! log {
+    // The `$(...)*` syntax is used to match against any number of arguments of any type
+    ($($arg:tt)*) => {
+        // Check if in debug mode and call `print!` if true
+        if cfg!(debug_assertions) {
+            print!($($arg)*);
+        }
+    };
+}
+
+fn device_error(error: u32) -> Result<(), UsbtmcErrors> {
+    match error {
+        0 => Ok(()),
+        ERROR_IO_TIMEOUT => Err(UsbtmcErrors::Timeout),
+        _ => Err(UsbtmcErrors::Vxi11Error(error)),
+    }
+}
+
+fn millis(duration: Duration) -> u32 {
+    duration.as_millis().min(u32::MAX as u128) as u32
+}
+
+/*
+* XDR encoding, RFC 4506
+*/
+struct XdrWriter(Vec<u8>);
+
+impl XdrWriter {
+    fn new() -> XdrWriter {
+        XdrWriter(Vec::new())
+    }
+
+    fn u32(mut self, value: u32) -> XdrWriter {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn opaque(mut self, data: &[u8]) -> XdrWriter {
+        self = self.u32(data.len() as u32);
+        self.0.extend_from_slice(data);
+        self.0.resize(self.0.len() + (4 - data.len() % 4) % 4, 0);
+        self
+    }
+}
+
+struct XdrReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> XdrReader<'a> {
+    fn u32(&mut self) -> Result<u32, UsbtmcErrors> {
+        if self.data.len() < 4 {
+            return Err(UsbtmcErrors::InvalidData);
+        }
+        let (value, rest) = self.data.split_at(4);
+        self.data = rest;
+        Ok(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+    }
+
+    fn opaque(&mut self) -> Result<&'a [u8], UsbtmcErrors> {
+        let size = self.u32()? as usize;
+        let padded = size + (4 - size % 4) % 4;
+        if self.data.len() < padded {
+            return Err(UsbtmcErrors::InvalidData);
+        }
+        let (value, rest) = self.data.split_at(padded);
+        self.data = rest;
+        Ok(&value[..size])
+    }
+}
+
+/*
+* RFC 5531 section 9 and 11. Sends one call with AUTH_NONE as a single record and returns the
+* results of the accepted reply, giving up after `timeout`.
+*/
+async fn rpc_call(
+    stream: &mut Async<TcpStream>,
+    timeout: Duration,
+    xid: u32,
+    program: u32,
+    version: u32,
+    procedure: u32,
+    params: XdrWriter,
+) -> Result<Vec<u8>, UsbtmcErrors> {
+    let call = XdrWriter::new()
+        .u32(xid)
+        .u32(RPC_CALL)
+        .u32(RPC_VERSION)
+        .u32(program)
+        .u32(version)
+        .u32(procedure)
+        .u32(0)
+        .u32(0)
+        .u32(0)
+        .u32(0);
+
+    let mut record = (RPC_LAST_FRAGMENT | (call.0.len() + params.0.len()) as u32)
+        .to_be_bytes()
+        .to_vec();
+    record.extend_from_slice(&call.0);
+    record.extend_from_slice(&params.0);
+
+    timeout_after(exchange(stream, &record, xid), timeout).await?
+}
+
+async fn exchange(
+    stream: &mut Async<TcpStream>,
+    record: &[u8],
+    xid: u32,
+) -> Result<Vec<u8>, UsbtmcErrors> {
+    stream.write_all(record).await.map_err(io_error)?;
+
+    let reply = read_record(stream).await?;
+    let mut reader = XdrReader { data: &reply };
+
+    if reader.u32()? != xid || reader.u32()? != RPC_REPLY || reader.u32()? != RPC_MSG_ACCEPTED {
+        return Err(UsbtmcErrors::RpcError);
+    }
+    let _verifier_flavor = reader.u32()?;
+    let _verifier = reader.opaque()?;
+    if reader.u32()? != RPC_SUCCESS {
+        return Err(UsbtmcErrors::RpcError);
+    }
+
+    Ok(reader.data.to_vec())
+}
+
+async fn read_record(stream: &mut Async<TcpStream>) -> Result<Vec<u8>, UsbtmcErrors> {
+    let mut record = Vec::new();
+
+    loop {
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await.map_err(io_error)?;
+        let header = u32::from_be_bytes(header);
+
+        let start = record.len();
+        record.resize(start + (header & !RPC_LAST_FRAGMENT) as usize, 0);
+        stream
+            .read_exact(&mut record[start..])
+            .await
+            .map_err(io_error)?;
+
+        if header & RPC_LAST_FRAGMENT != 0 {
+            return Ok(record);
+        }
+    }
+}
+
+/*
+* Asks the portmapper at `address` for the port of the VXI-11 core channel
+*/
+async fn get_core_port(address: SocketAddr, timeout: Duration) -> Result<u16, UsbtmcErrors> {
+    let mut stream = connect(address, timeout).await?;
+
+    let params = XdrWriter::new()
+        .u32(DEVICE_CORE_PROGRAM)
+        .u32(DEVICE_CORE_VERSION)
+        .u32(IPPROTO_TCP)
+        .u32(0);
+    let reply = rpc_call(
+        &mut stream,
+        timeout,
+        1,
+        PORTMAPPER_PROGRAM,
+        PORTMAPPER_VERSION,
+        PORTMAPPER_PROC_GETPORT,
+        params,
+    )
+    .await?;
+
+    let port = XdrReader { data: &reply }.u32()?;
+    log!("VXI-11 core channel port: {}\n", port);
+
+    match u16::try_from(port) {
+        Ok(0) | Err(_) => Err(UsbtmcErrors::DeviceNotFound),
+        Ok(port) => Ok(port),
+    }
+}
+
+/*
+* Opens a link to `device`, such as "inst0" or "gpib0,5", on the instrument at `address`.
+* `address` is "host" or "host:port" of the portmapper, the port defaults to 111.
+*/
+pub fn open_vxi11(address: &str, device: &str) -> Result<Vxi11, UsbtmcErrors> {
+    block_on(open_vxi11_async(address, device))
+}
+
+pub async fn open_vxi11_async(address: &str, device: &str) -> Result<Vxi11, UsbtmcErrors> {
+    let portmapper = resolve(address, PORTMAPPER_PORT)?
+        .into_iter()
+        .next()
+        .ok_or(UsbtmcErrors::DeviceNotFound)?;
+    let core_port = get_core_port(portmapper, VXI11_DEFAULT_TIMEOUT).await?;
+
+    let core_address = SocketAddr::new(portmapper.ip(), core_port);
+    let mut stream = connect(core_address, VXI11_DEFAULT_TIMEOUT).await?;
+    let (link_id, abort_port, max_recv_size) = create_link(&mut stream, device).await?;
+
+    Ok(Vxi11 {
+        stream,
+        core_address,
+        device: device.to_string(),
+        out_of_frame: false,
+        xid: 1,
+        link_id,
+        abort_address: SocketAddr::new(portmapper.ip(), abort_port),
+        max_recv_size,
+        timeout: VXI11_DEFAULT_TIMEOUT,
+        lock_timeout: Duration::ZERO,
+        term_char: b'\n',
+        term_char_enabled: false,
+    })
+}
+
+/*
+* VXI-11 section B.6.2 create_link. Returns the link ID, the abort port and maxRecvSize.
+*/
+async fn create_link(
+    stream: &mut Async<TcpStream>,
+    device: &str,
+) -> Result<(u32, u16, u32), UsbtmcErrors> {
+    let params = XdrWriter::new()
+        .u32(std::process::id())
+        .u32(0)
+        .u32(0)
+        .opaque(device.as_bytes());
+    let reply = rpc_call(
+        stream,
+        VXI11_DEFAULT_TIMEOUT + VXI11_RPC_MARGIN,
+        1,
+        DEVICE_CORE_PROGRAM,
+        DEVICE_CORE_VERSION,
+        CREATE_LINK,
+        params,
+    )
+    .await?;
+
+    let mut reader = XdrReader { data: &reply };
+    device_error(reader.u32()?)?;
+    let link_id = reader.u32()?;
+    let abort_port = reader.u32()? as u16;
+    let max_recv_size = reader.u32()?;
+    log!(
+        "Link {} to {}, abort port {}, max receive size {}\n",
+        link_id,
+        device,
+        abort_port,
+        max_recv_size
+    );
+
+    Ok((link_id, abort_port, max_recv_size.max(1)))
+}
+
+/*
+* Replaces a core channel that is out of frame with a new connection and link. The instrument
+* destroys the old link, with its locks, when the old connection closes, and abort handles taken
+* before this no longer apply.
+*/
+async fn relink(vxi11: &mut Vxi11) -> Result<(), UsbtmcErrors> {
+    log!("Reconnecting the VXI-11 core channel\n");
+
+    let mut stream = connect(vxi11.core_address, VXI11_DEFAULT_TIMEOUT).await?;
+    let (link_id, abort_port, max_recv_size) = create_link(&mut stream, &vxi11.device).await?;
+
+    vxi11.stream = stream;
+    vxi11.link_id = link_id;
+    vxi11.abort_address.set_port(abort_port);
+    vxi11.max_recv_size = max_recv_size;
+    vxi11.out_of_frame = false;
+
+    Ok(())
+}
+
+/*
+* Calls `procedure` on the core channel. The device may take io_timeout plus `lock_wait` before it
+* replies. A call that times out can leave part of a record on the stream, so the channel is
+* replaced before the next call.
+*/
+async fn core_call(
+    vxi11: &mut Vxi11,
+    procedure: u32,
+    lock_wait: Duration,
+    params: XdrWriter,
+) -> Result<Vec<u8>, UsbtmcErrors> {
+    if vxi11.out_of_frame {
+        relink(vxi11).await?;
+    }
+
+    vxi11.xid = vxi11.xid.wrapping_add(1);
+
+    let result = rpc_call(
+        &mut vxi11.stream,
+        vxi11.timeout + lock_wait + VXI11_RPC_MARGIN,
+        vxi11.xid,
+        DEVICE_CORE_PROGRAM,
+        DEVICE_CORE_VERSION,
+        procedure,
+        params,
+    )
+    .await;
+
+    if matches!(result, Err(UsbtmcErrors::Timeout)) {
+        vxi11.out_of_frame = true;
+    }
+
+    result
+}
+
+fn lock_flags(vxi11: &Vxi11) -> u32 {
+    if vxi11.lock_timeout.is_zero() {
+        0
+    } else {
+        FLAG_WAITLOCK
+    }
+}
+
+/*
+* VXI-11 section B.5.2 Device_GenericParms, used by readstb, trigger, clear, remote and local
+*/
+async fn generic_call(vxi11: &mut Vxi11, procedure: u32) -> Result<Vec<u8>, UsbtmcErrors> {
+    let params = XdrWriter::new()
+        .u32(vxi11.link_id)
+        .u32(lock_flags(vxi11))
+        .u32(millis(vxi11.lock_timeout))
+        .u32(millis(vxi11.timeout));
+    let lock_wait = vxi11.lock_timeout;
+    let reply = core_call(vxi11, procedure, lock_wait, params).await?;
+
+    device_error(XdrReader { data: &reply }.u32()?)?;
+
+    Ok(reply)
+}
+
+/*
+* Sets how long device operations wait for a lock held by another link. Zero fails at once.
+*/
+pub fn set_vxi11_lock_timeout(vxi11: &mut Vxi11, lock_timeout: Duration) {
+    vxi11.lock_timeout = lock_timeout;
+}
+
+/*
+* Sets the character that terminates responses. Defaults to '\n'. With `enabled` the device also
+* ends a device_read when it sends this character.
+*/
+pub fn set_vxi11_term_char(vxi11: &mut Vxi11, term_char: u8, enabled: bool) {
+    vxi11.term_char = term_char;
+    vxi11.term_char_enabled = enabled;
+}
+
+/*
+* VXI-11 section B.6.5 device_readstb
+*/
+pub fn vxi11_read_status_byte(vxi11: &mut Vxi11) -> Result<u8, UsbtmcErrors> {
+    block_on(vxi11_read_status_byte_async(vxi11))
+}
+
+pub async fn vxi11_read_status_byte_async(vxi11: &mut Vxi11) -> Result<u8, UsbtmcErrors> {
+    let reply = generic_call(vxi11, DEVICE_READSTB).await?;
+
+    let mut reader = XdrReader { data: &reply };
+    let _error = reader.u32()?;
+
+    Ok(reader.u32()? as u8)
+}
+
+/*
+* VXI-11 section B.6.6 device_trigger
+*/
+pub fn vxi11_trigger(vxi11: &mut Vxi11) -> Result<(), UsbtmcErrors> {
+    block_on(vxi11_trigger_async(vxi11))
+}
+
+pub async fn vxi11_trigger_async(vxi11: &mut Vxi11) -> Result<(), UsbtmcErrors> {
+    generic_call(vxi11, DEVICE_TRIGGER).await.map(|_| ())
+}
+
+/*
+* VXI-11 section B.6.7 device_clear
+*/
+pub fn vxi11_clear(vxi11: &mut Vxi11) -> Result<(), UsbtmcErrors> {
+    block_on(vxi11_clear_async(vxi11))
+}
+
+pub async fn vxi11_clear_async(vxi11: &mut Vxi11) -> Result<(), UsbtmcErrors> {
+    generic_call(vxi11, DEVICE_CLEAR).await.map(|_| ())
+}
+
+/*
+* VXI-11 section B.6.8 and B.6.9 device_remote and device_local
+*/
+pub fn vxi11_remote(vxi11: &mut Vxi11) -> Result<(), UsbtmcErrors> {
+    block_on(vxi11_remote_async(vxi11))
+}
+
+pub async fn vxi11_remote_async(vxi11: &mut Vxi11) -> Result<(), UsbtmcErrors> {
+    generic_call(vxi11, DEVICE_REMOTE).await.map(|_| ())
+}
+
+pub fn vxi11_go_to_local(vxi11: &mut Vxi11) -> Result<(), UsbtmcErrors> {
+    block_on(vxi11_go_to_local_async(vxi11))
+}
+
+pub async fn vxi11_go_to_local_async(vxi11: &mut Vxi11) -> Result<(), UsbtmcErrors> {
+    generic_call(vxi11, DEVICE_LOCAL).await.map(|_| ())
+}
+
+/*
+* VXI-11 section B.6.10 device_lock. Waits up to `wait` if another link holds the lock.
+*/
+pub fn vxi11_lock(vxi11: &mut Vxi11, wait: Duration) -> Result<(), UsbtmcErrors> {
+    block_on(vxi11_lock_async(vxi11, wait))
+}
+
+pub async fn vxi11_lock_async(vxi11: &mut Vxi11, wait: Duration) -> Result<(), UsbtmcErrors> {
+    let flags = if wait.is_zero() { 0 } else { FLAG_WAITLOCK };
+    let params = XdrWriter::new()
+        .u32(vxi11.link_id)
+        .u32(flags)
+        .u32(millis(wait));
+    let reply = core_call(vxi11, DEVICE_LOCK, wait, params).await?;
+
+    device_error(XdrReader { data: &reply }.u32()?)
+}
+
+/*
+* VXI-11 section B.6.11 device_unlock
+*/
+pub fn vxi11_unlock(vxi11: &mut Vxi11) -> Result<(), UsbtmcErrors> {
+    block_on(vxi11_unlock_async(vxi11))
+}
+
+pub async fn vxi11_unlock_async(vxi11: &mut Vxi11) -> Result<(), UsbtmcErrors> {
+    let params = XdrWriter::new().u32(vxi11.link_id);
+    let reply = core_call(vxi11, DEVICE_UNLOCK, Duration::ZERO, params).await?;
+
+    device_error(XdrReader { data: &reply }.u32()?)
+}
+
+/*
+* VXI-11 section B.6.4 destroy_link. Dropping the session without closing leaves the instrument to
+* clean up the link when the connection closes.
+*/
+pub fn close_vxi11(vxi11: Vxi11) -> Result<(), UsbtmcErrors> {
+    block_on(close_vxi11_async(vxi11))
+}
+
+pub async fn close_vxi11_async(mut vxi11: Vxi11) -> Result<(), UsbtmcErrors> {
+    let params = XdrWriter::new().u32(vxi11.link_id);
+    let reply = core_call(&mut vxi11, DESTROY_LINK, Duration::ZERO, params).await?;
+
+    device_error(XdrReader { data: &reply }.u32()?)
+}
+
+pub fn vxi11_abort_handle(vxi11: &Vxi11) -> AbortHandle {
+    AbortHandle {
+        address: vxi11.abort_address,
+        link_id: vxi11.link_id,
+    }
+}
+
+/*
+* VXI-11 section B.6.15 device_abort. The blocked call on the core channel returns with an abort
+* error.
+*/
+pub fn vxi11_abort(handle: &AbortHandle) -> Result<(), UsbtmcErrors> {
+    block_on(vxi11_abort_async(handle))
+}
+
+pub async fn vxi11_abort_async(handle: &AbortHandle) -> Result<(), UsbtmcErrors> {
+    let mut stream = connect(handle.address, VXI11_DEFAULT_TIMEOUT).await?;
+
+    let params = XdrWriter::new().u32(handle.link_id);
+    let reply = rpc_call(
+        &mut stream,
+        VXI11_DEFAULT_TIMEOUT,
+        1,
+        DEVICE_ASYNC_PROGRAM,
+        DEVICE_ASYNC_VERSION,
+        DEVICE_ABORT,
+        params,
+    )
+    .await?;
+
+    device_error(XdrReader { data: &reply }.u32()?)
+}
+
+/*
+* VXI-11 section B.6.12 device_read, repeated until the device sets END or, with `term_char_enabled`,
+* CHR
+*/
+async fn device_read<F>(
+    vxi11: &mut Vxi11,
+    term_char_enabled: bool,
+    on_payload: &mut F,
+) -> Result<(), UsbtmcErrors>
+where
+    F: FnMut(&[u8]) -> Result<(), UsbtmcErrors>,
+{
+    loop {
+        let flags = lock_flags(vxi11)
+            | if term_char_enabled {
+                FLAG_TERMCHRSET
+            } else {
+                0
+            };
+
+        let params = XdrWriter::new()
+            .u32(vxi11.link_id)
+            .u32(VXI11_READ_SIZE)
+            .u32(millis(vxi11.timeout))
+            .u32(millis(vxi11.lock_timeout))
+            .u32(flags)
+            .u32(vxi11.term_char as u32);
+        let lock_wait = vxi11.lock_timeout;
+        let reply = core_call(vxi11, DEVICE_READ, lock_wait, params).await?;
+
+        let mut reader = XdrReader { data: &reply };
+        device_error(reader.u32()?)?;
+        let reason = reader.u32()?;
+        let data = reader.opaque()?;
+        log!("device_read: {} bytes, reason {:#x}\n", data.len(), reason);
+
+        on_payload(data)?;
+
+        if reason & (REASON_END | REASON_CHR) != 0 {
+            return Ok(());
+        }
+    }
+}
+
+impl Transport for Vxi11 {
+    /*
+     * VXI-11 section B.6.3 device_write. Messages longer than maxRecvSize are split, END is set on
+     * the last part only.
+     */
+    async fn write_message(&mut self, data: &[u8]) -> Result<(), UsbtmcErrors> {
+        let mut remaining = data;
+
+        loop {
+            let size = remaining.len().min(self.max_recv_size as usize);
+            let last = size == remaining.len();
+            let flags = lock_flags(self) | if last { FLAG_END } else { 0 };
+
+            let params = XdrWriter::new()
+                .u32(self.link_id)
+                .u32(millis(self.timeout))
+                .u32(millis(self.lock_timeout))
+                .u32(flags)
+                .opaque(&remaining[..size]);
+            let lock_wait = self.lock_timeout;
+            let reply = core_call(self, DEVICE_WRITE, lock_wait, params).await?;
+
+            let mut reader = XdrReader { data: &reply };
+            device_error(reader.u32()?)?;
+            let written = (reader.u32()? as usize).min(size);
+            log!("device_write: {} bytes\n", written);
+
+            remaining = &remaining[written..];
+            if remaining.is_empty() {
+                return Ok(());
+            }
+            if written == 0 {
+                return Err(UsbtmcErrors::InvalidData);
+            }
+        }
+    }
+
+    async fn read_message<F>(&mut self, on_payload: &mut F) -> Result<(), UsbtmcErrors>
+    where
+        F: FnMut(&[u8]) -> Result<(), UsbtmcErrors> + Send,
+    {
+        let term_char_enabled = self.term_char_enabled;
+        device_read(self, term_char_enabled, on_payload).await
+    }
+
+    async fn read_block_message<F>(&mut self, on_payload: &mut F) -> Result<(), UsbtmcErrors>
+    where
+        F: FnMut(&[u8]) -> Result<(), UsbtmcErrors> + Send,
+    {
+        device_read(self, false, on_payload).await
+    }
+
+    async fn clear(&mut self) -> Result<(), UsbtmcErrors> {
+        vxi11_clear_async(self).await
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn term_char(&self) -> u8 {
+        self.term_char
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rscpi::hislip::*;
+use rscpi::usbtmc::*;
+use rscpi::*;
+
+// small enough that longer commands are split into several Data messages
+const MAX_MESSAGE_SIZE: u64 = 32;
+const LARGE_BLOCK_SIZE: usize = 3 * 1024 * 1024;
+
+#[derive(Default)]
+struct Server {
+    next_session: u16,
+    async_channels: HashMap<u16, Arc<Mutex<TcpStream>>>,
+    lock_holder: Option<u16>,
+    overlapped: bool,
+    triggers: u32,
+    // message ID and RMT-delivered flag of every complete message received
+    messages: Vec<(u32, u8)>,
+    // maximum message size the client asked for
+    client_max_message_size: u64,
+}
+
+struct Message {
+    message_type: u8,
+    control_code: u8,
+    parameter: u32,
+    payload: Vec<u8>,
+}
+
+fn receive(stream: &mut TcpStream) -> Option<Message> {
+    let mut header = [0u8; 16];
+    stream.read_exact(&mut header).ok()?;
+    assert_eq!(&header[0..2], b"HS");
+
+    let mut length = [0u8; 8];
+    length.copy_from_slice(&header[8..16]);
+    let mut payload = vec![0u8; u64::from_be_bytes(length) as usize];
+    stream.read_exact(&mut payload).ok()?;
+
+    Some(Message {
+        message_type: header[2],
+        control_code: header[3],
+        parameter: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+        payload,
+    })
+}
+
+fn send(
+    stream: &mut TcpStream,
+    message_type: u8,
+    control_code: u8,
+    parameter: u32,
+    payload: &[u8],
+) {
+    let mut message = b"HS".to_vec();
+    message.push(message_type);
+    message.push(control_code);
+    message.extend_from_slice(&parameter.to_be_bytes());
+    message.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    message.extend_from_slice(payload);
+    let _ = stream.write_all(&message);
+}
+
+fn respond(command: &[u8]) -> Option<Vec<u8>> {
+    let command = String::from_utf8_lossy(command).trim().to_string();
+
+    if command == "*IDN?" {
+        Some(b"SIM,HISLIP,0,1.0\n".to_vec())
+    } else if command == "CURVE?" {
+        // sent in messages larger than the client asked for
+        let mut block = format!("#7{}", LARGE_BLOCK_SIZE).into_bytes();
+        block.extend((0..LARGE_BLOCK_SIZE).map(|i| i as u8));
+        block.push(b'\n');
+        Some(block)
+    } else if command == ":WAV:DATA?" {
+        let mut block = b"#240".to_vec();
+        block.extend((0..40).map(|i| i as u8));
+        block.push(b'\n');
+        Some(block)
+    } else {
+        command
+            .strip_prefix("ECHO? ")
+            .map(|text| format!("{}\n", text).into_bytes())
+    }
+}
+
+fn serve_sync(server: Arc<Mutex<Server>>, mut stream: TcpStream, session: u16) {
+    let mut input: Vec<u8> = Vec::new();
+    // RMT-delivered is carried by the first Data or DataEnd of a message
+    let mut rmt_delivered = 0;
+    // message ID of a response that was only partly sent
+    let mut unfinished = None;
+
+    while let Some(message) = receive(&mut stream) {
+        match message.message_type {
+            // Data, DataEnd
+            6 | 7 => {
+                assert!(message.payload.len() as u64 <= MAX_MESSAGE_SIZE);
+                if input.is_empty() {
+                    rmt_delivered = message.control_code;
+                }
+                input.extend_from_slice(&message.payload);
+                if message.message_type == 6 {
+                    continue;
+                }
+
+                server
+                    .lock()
+                    .unwrap()
+                    .messages
+                    .push((message.parameter, rmt_delivered));
+
+                // synchronized mode, a new message interrupts the unfinished response
+                if let Some(parameter) = unfinished.take() {
+                    send(&mut stream, 13, 0, parameter, &[]);
+                }
+
+                let command = std::mem::take(&mut input);
+                if command.starts_with(b"SRQ") {
+                    let channel = server.lock().unwrap().async_channels[&session].clone();
+                    send(&mut channel.lock().unwrap(), 20, 0x50, 0, &[]);
+                } else if command.starts_with(b"PARTIAL?") {
+                    send(&mut stream, 6, 0, message.parameter, b"unfinished ");
+                    unfinished = Some(message.parameter);
+                } else if let Some(response) = respond(&command) {
+                    // the response comes in two parts to exercise Data followed by DataEnd
+                    let (first, rest) = response.split_at(response.len() / 2);
+                    send(&mut stream, 6, 0, message.parameter, first);
+                    send(&mut stream, 7, 0, message.parameter, rest);
+                }
+            }
+            // Trigger
+            12 => server.lock().unwrap().triggers += 1,
+            // DeviceClearComplete
+            8 => {
+                input.clear();
+                let overlapped = message.control_code & 1;
+                server.lock().unwrap().overlapped = overlapped != 0;
+                send(&mut stream, 9, overlapped, 0, &[]);
+            }
+            other => panic!("unexpected message {} on the synchronous channel", other),
+        }
+    }
+}
+
+fn serve_async(server: Arc<Mutex<Server>>, mut stream: TcpStream, session: u16) {
+    let writer = Arc::new(Mutex::new(stream.try_clone().unwrap()));
+    server
+        .lock()
+        .unwrap()
+        .async_channels
+        .insert(session, writer.clone());
+    send(&mut writer.lock().unwrap(), 18, 0, 0x5A5A, &[]);
+
+    while let Some(message) = receive(&mut stream) {
+        let mut server = server.lock().unwrap();
+        let mut writer = writer.lock().unwrap();
+
+        match message.message_type {
+            // AsyncMaximumMessageSize
+            15 => {
+                let mut size = [0u8; 8];
+                size.copy_from_slice(&message.payload);
+                server.client_max_message_size = u64::from_be_bytes(size);
+                send(&mut writer, 16, 0, 0, &MAX_MESSAGE_SIZE.to_be_bytes());
+            }
+            // AsyncLock
+            4 => {
+                let control_code = if message.control_code == 1 {
+                    if server.lock_holder.is_none_or(|holder| holder == session) {
+                        server.lock_holder = Some(session);
+                        1
+                    } else {
+                        0
+                    }
+                } else if server.lock_holder == Some(session) {
+                    server.lock_holder = None;
+                    1
+                } else {
+                    3
+                };
+                send(&mut writer, 5, control_code, 0, &[]);
+            }
+            // AsyncLockInfo
+            24 => {
+                let locked = server.lock_holder.is_some();
+                send(&mut writer, 25, locked as u8, locked as u32, &[]);
+            }
+            // AsyncStatusQuery
+            21 => send(&mut writer, 22, 0x10, 0, &[]),
+            // AsyncDeviceClear
+            19 => send(&mut writer, 23, 1, 0, &[]),
+            // AsyncRemoteLocalControl
+            10 => send(&mut writer, 11, 0, 0, &[]),
+            other => panic!("unexpected message {} on the asynchronous channel", other),
+        }
+    }
+}
+
+/*
+* HiSLIP stand-in on a local port. The first message on a connection decides whether it is a
+* synchronous or an asynchronous channel.
+*/
+fn start_instrument() -> (String, Arc<Mutex<Server>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let server = Arc::new(Mutex::new(Server::default()));
+    let accept_server = server.clone();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let server = accept_server.clone();
+
+            thread::spawn(move || {
+                let Some(message) = receive(&mut stream) else {
+                    return;
+                };
+
+                match message.message_type {
+                    // Initialize
+                    0 => {
+                        // protocol version 1.0, no vendor ID
+                        assert_eq!(message.parameter, 0x0100_0000);
+                        if message.payload != b"hislip0" {
+                            send(&mut stream, 2, 0, 0, b"unknown sub-address");
+                            return;
+                        }
+                        let session = {
+                            let mut server = server.lock().unwrap();
+                            server.next_session += 1;
+                            server.next_session
+                        };
+                        send(&mut stream, 1, 0, 0x0100_0000 | session as u32, &[]);
+                        serve_sync(server, stream, session);
+                    }
+                    // AsyncInitialize
+                    17 => serve_async(server, stream, message.parameter as u16),
+                    other => panic!("unexpected first message {}", other),
+                }
+            });
+        }
+    });
+
+    (address, server)
+}
+
+#[test]
+fn hislip_query() {
+    let (address, server) = start_instrument();
+    let mut hislip = open_hislip(&address, "hislip0").unwrap();
+    assert!(!hislip_is_overlapped(&hislip));
+
+    assert_eq!(query(&mut hislip, "*IDN?").unwrap(), "SIM,HISLIP,0,1.0");
+
+    let text = "a command longer than the maximum message size";
+    assert_eq!(
+        query(&mut hislip, &format!("ECHO? {}", text)).unwrap(),
+        text
+    );
+
+    let data = query_binary_data(&mut hislip, ":WAV:DATA?").unwrap();
+    assert_eq!(data, (0..40).collect::<Vec<u8>>());
+
+    // message IDs go up by two, RMT-delivered follows every complete response
+    let messages = server.lock().unwrap().messages.clone();
+    assert_eq!(
+        messages,
+        vec![(0xFFFF_FF00, 0), (0xFFFF_FF02, 1), (0xFFFF_FF04, 1)]
+    );
+}
+
+#[test]
+fn hislip_large_messages() {
+    let (address, server) = start_instrument();
+    let mut hislip = open_hislip(&address, "hislip0").unwrap();
+    assert_eq!(server.lock().unwrap().client_max_message_size, 1024 * 1024);
+
+    // each DataEnd is larger than the client maximum, the payload is read in pieces
+    let data = query_binary_data(&mut hislip, "CURVE?").unwrap();
+    assert_eq!(data.len(), LARGE_BLOCK_SIZE);
+    assert!(data.iter().enumerate().all(|(i, &byte)| byte == i as u8));
+
+    // an unread large response is skipped by device clear
+    write(&mut hislip, "CURVE?").unwrap();
+    hislip_clear(&mut hislip).unwrap();
+    assert_eq!(query(&mut hislip, "*IDN?").unwrap(), "SIM,HISLIP,0,1.0");
+}
+
+#[test]
+fn hislip_device_clear() {
+    let (address, _server) = start_instrument();
+    let mut hislip = open_hislip(&address, "hislip0").unwrap();
+
+    // the response is left unread, device clear discards it
+    write_binary_message(&mut hislip, b"*IDN?\n");
+    hislip_clear(&mut hislip).unwrap();
+    assert!(!hislip_is_overlapped(&hislip));
+
+    assert_eq!(query(&mut hislip, "*IDN?").unwrap(), "SIM,HISLIP,0,1.0");
+
+    hislip_clear_with_mode(&mut hislip, true).unwrap();
+    assert!(hislip_is_overlapped(&hislip));
+    assert_eq!(query(&mut hislip, "*IDN?").unwrap(), "SIM,HISLIP,0,1.0");
+}
+
+#[test]
+fn hislip_stale_responses() {
+    let (address, _server) = start_instrument();
+    let mut hislip = open_hislip(&address, "hislip0").unwrap();
+
+    // an unread response is dropped by the next query
+    write_binary_message(&mut hislip, b"*IDN?\n");
+    assert_eq!(query(&mut hislip, "ECHO? first").unwrap(), "first");
+
+    // so is the start of a response the server interrupts
+    write_binary_message(&mut hislip, b"PARTIAL?\n");
+    assert_eq!(query(&mut hislip, "ECHO? second").unwrap(), "second");
+    assert_eq!(query(&mut hislip, "*IDN?").unwrap(), "SIM,HISLIP,0,1.0");
+}
+
+fn write_binary_message(hislip: &mut Hislip, data: &[u8]) {
+    use rscpi::transport::Transport;
+
+    futures_lite::future::block_on(hislip.write_message(data)).unwrap();
+}
+
+#[test]
+fn hislip_locking() {
+    let (address, _server) = start_instrument();
+    let mut first = open_hislip(&address, "hislip0").unwrap();
+    let mut second = open_hislip(&address, "hislip0").unwrap();
+
+    hislip_lock(&mut first, "", Duration::ZERO).unwrap();
+    assert_eq!(hislip_lock_info(&mut second).unwrap(), (true, 1));
+    assert!(matches!(
+        hislip_lock(&mut second, "", Duration::ZERO),
+        Err(UsbtmcErrors::Timeout)
+    ));
+    assert!(matches!(
+        hislip_unlock(&mut second),
+        Err(UsbtmcErrors::LockFailed)
+    ));
+
+    hislip_unlock(&mut first).unwrap();
+    hislip_lock(&mut second, "", Duration::ZERO).unwrap();
+    hislip_unlock(&mut second).unwrap();
+}
+
+#[test]
+fn hislip_status_and_srq() {
+    let (address, server) = start_instrument();
+    let mut hislip = open_hislip(&address, "hislip0").unwrap();
+
+    assert_eq!(hislip_read_status_byte(&mut hislip).unwrap(), 0x10);
+
+    write(&mut hislip, "SRQ").unwrap();
+    assert_eq!(hislip_wait_for_srq(&mut hislip).unwrap(), 0x50);
+
+    hislip_trigger(&mut hislip).unwrap();
+    hislip_ren_control(&mut hislip, true).unwrap();
+    hislip_local_lockout(&mut hislip).unwrap();
+    hislip_go_to_local(&mut hislip).unwrap();
+
+    assert_eq!(server.lock().unwrap().triggers, 1);
+}
+
+#[test]
+fn hislip_srq_timeout() {
+    use rscpi::transport::Transport;
+    use std::time::Instant;
+
+    let (address, _server) = start_instrument();
+    let mut hislip = open_hislip(&address, "hislip0").unwrap();
+    hislip.set_timeout(Duration::from_millis(200));
+
+    // nothing requests service, the wait ends with the session timeout
+    let start = Instant::now();
+    assert!(matches!(
+        hislip_wait_for_srq(&mut hislip),
+        Err(UsbtmcErrors::Timeout)
+    ));
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    assert_eq!(query(&mut hislip, "*IDN?").unwrap(), "SIM,HISLIP,0,1.0");
+}
+
+#[test]
+fn hislip_errors() {
+    let (address, _server) = start_instrument();
+
+    assert!(matches!(
+        open_hislip(&address, "hislip9"),
+        Err(UsbtmcErrors::HislipError(0))
+    ));
+}
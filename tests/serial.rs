@@ -0,0 +1,218 @@
+#![cfg(unix)]
+
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rscpi::serial::*;
+use rscpi::usbtmc::UsbtmcErrors;
+use rscpi::*;
+use serialport::{SerialPort, TTYPort};
+
+// 10 bytes of waveform data, with a "\r\n" inside the block
+const WAVEFORM: &[u8] = b"\x00\r\n\x03#3\r\x06\x07\n";
+
+struct StandIn {
+    path: String,
+    // arrival time of every command
+    commands: Arc<Mutex<Vec<Instant>>>,
+    // the slave end stays open until the port under test has been opened
+    _slave: TTYPort,
+}
+
+fn respond(line: &[u8]) -> Vec<u8> {
+    // the stand-in answers with the terminator it was sent
+    let (command, terminator) = match line.strip_suffix(b"\r") {
+        Some(command) => (command, &b"\r\n"[..]),
+        None => (line, &b"\n"[..]),
+    };
+
+    let mut response = match command {
+        b"*IDN?" => b"SIM,SERIAL,0,1.0".to_vec(),
+        b"LINE?" => format!("{:?}", String::from_utf8_lossy(line)).into_bytes(),
+        b":WAV:DATA?" => {
+            let mut block = b"#210".to_vec();
+            block.extend_from_slice(WAVEFORM);
+            block
+        }
+        _ => return Vec::new(),
+    };
+    response.extend_from_slice(terminator);
+    response
+}
+
+/*
+* Simulated instrument on the master side of a pseudo-terminal pair, commands end with '\n',
+* "HANG?" never gets a response and the response to "TRICKLE?" comes a byte at a time for two
+* seconds without ever ending
+*/
+fn start_instrument() -> StandIn {
+    let (mut master, slave) = TTYPort::pair().unwrap();
+    let path = slave.name().unwrap();
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    let instrument_commands = commands.clone();
+
+    thread::spawn(move || {
+        let mut input: Vec<u8> = Vec::new();
+        let mut buffer = [0u8; 256];
+
+        loop {
+            let size = match master.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(size) => size,
+                Err(error) if error.kind() == std::io::ErrorKind::TimedOut => continue,
+                // EIO once every slave file descriptor is closed
+                Err(_) => break,
+            };
+            input.extend_from_slice(&buffer[..size]);
+
+            while let Some(end) = input.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = input.drain(..=end).collect();
+                instrument_commands.lock().unwrap().push(Instant::now());
+
+                if line.starts_with(b"TRICKLE?") {
+                    for _ in 0..20 {
+                        let _ = master.write_all(b"x");
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                    continue;
+                }
+
+                let response = respond(&line[..line.len() - 1]);
+                if master.write_all(&response).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    StandIn {
+        path,
+        commands,
+        _slave: slave,
+    }
+}
+
+#[test]
+fn serial_query() {
+    let stand_in = start_instrument();
+    let mut serial = open_serial(&stand_in.path, &SerialSettings::default()).unwrap();
+
+    write(&mut serial, "*RST").unwrap();
+    assert_eq!(query(&mut serial, "*IDN?").unwrap(), "SIM,SERIAL,0,1.0");
+    assert_eq!(query(&mut serial, "LINE?").unwrap(), "\"LINE?\"");
+
+    let data = query_binary_data(&mut serial, ":WAV:DATA?").unwrap();
+    assert_eq!(data, WAVEFORM);
+}
+
+#[test]
+fn serial_terminators() {
+    let stand_in = start_instrument();
+    let settings = SerialSettings {
+        baud_rate: 115200,
+        parity: Parity::Even,
+        flow_control: FlowControl::Software,
+        write_terminator: b"\r\n".to_vec(),
+        read_terminator: b"\r\n".to_vec(),
+        ..Default::default()
+    };
+    let mut serial = open_serial(&stand_in.path, &settings).unwrap();
+
+    assert_eq!(query(&mut serial, "*IDN?").unwrap(), "SIM,SERIAL,0,1.0");
+    assert_eq!(query(&mut serial, "LINE?").unwrap(), "\"LINE?\\r\"");
+
+    let data = query_binary_data(&mut serial, ":WAV:DATA?").unwrap();
+    assert_eq!(data, WAVEFORM);
+
+    set_serial_terminators(&mut serial, b"\n", b"\n").unwrap();
+    assert_eq!(query(&mut serial, "LINE?").unwrap(), "\"LINE?\"");
+    assert!(set_serial_terminators(&mut serial, b"", b"\n").is_err());
+}
+
+#[test]
+fn serial_inter_command_delay() {
+    let stand_in = start_instrument();
+    let settings = SerialSettings {
+        inter_command_delay: Duration::from_millis(200),
+        ..Default::default()
+    };
+    let mut serial = open_serial(&stand_in.path, &settings).unwrap();
+
+    query(&mut serial, "*IDN?").unwrap();
+    write(&mut serial, "*RST").unwrap();
+    query(&mut serial, "*IDN?").unwrap();
+
+    let commands = stand_in.commands.lock().unwrap();
+    assert_eq!(commands.len(), 3);
+    for pair in commands.windows(2) {
+        assert!(pair[1] - pair[0] >= Duration::from_millis(150));
+    }
+}
+
+#[test]
+fn serial_timeout() {
+    use rscpi::transport::Transport;
+
+    let stand_in = start_instrument();
+    let mut serial = open_serial(&stand_in.path, &SerialSettings::default()).unwrap();
+    serial.set_timeout(Duration::from_millis(200));
+
+    assert!(matches!(
+        query(&mut serial, "HANG?"),
+        Err(UsbtmcErrors::Timeout)
+    ));
+
+    clear(&mut serial);
+    assert_eq!(query(&mut serial, "*IDN?").unwrap(), "SIM,SERIAL,0,1.0");
+}
+
+#[test]
+fn serial_response_deadline() {
+    use rscpi::transport::Transport;
+
+    let stand_in = start_instrument();
+    let mut serial = open_serial(&stand_in.path, &SerialSettings::default()).unwrap();
+    serial.set_timeout(Duration::from_millis(300));
+
+    // every byte arrives well within the timeout, the response as a whole does not
+    let start = Instant::now();
+    assert!(matches!(
+        query(&mut serial, "TRICKLE?"),
+        Err(UsbtmcErrors::Timeout)
+    ));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn serial_does_not_block_executor() {
+    let stand_in = start_instrument();
+    let mut serial = open_serial(&stand_in.path, &SerialSettings::default()).unwrap();
+
+    // a timer on the same thread fires while the port waits for a response that never comes
+    let start = Instant::now();
+    let waiting = futures_lite::future::block_on(futures_lite::future::or(
+        async {
+            query_async(&mut serial, "HANG?").await.unwrap();
+            false
+        },
+        async {
+            futures_timer::Delay::new(Duration::from_millis(100)).await;
+            true
+        },
+    ));
+    assert!(waiting);
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+fn clear(serial: &mut Serial) {
+    use rscpi::transport::Transport;
+
+    futures_lite::future::block_on(serial.clear()).unwrap();
+}
+
+#[test]
+fn serial_errors() {
+    assert!(open_serial("/dev/rscpi-missing", &SerialSettings::default()).is_err());
+}
@@ -0,0 +1,157 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use rscpi::tcp::*;
+use rscpi::transport::Transport;
+use rscpi::usbtmc::UsbtmcErrors;
+use rscpi::*;
+
+// 10 bytes of waveform data, two of them newlines
+const WAVEFORM: &[u8] = b"\n\x01\x02#3\n\x05\x06\x07\x08";
+
+/*
+* Simulated instrument on a local port. Every connection is served on its own thread, queries are
+* answered line by line and "HANG?" never gets a response.
+*/
+fn start_instrument() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let reader = BufReader::new(stream.try_clone().unwrap());
+                for line in reader.lines() {
+                    let Ok(line) = line else { break };
+                    let response: Vec<u8> = match line.trim() {
+                        "*IDN?" => b"SIM,TCP,0,1.0\n".to_vec(),
+                        "DOUBLE?" => b"1\n2\n".to_vec(),
+                        "LABEL?" => b"PROBE#15\n".to_vec(),
+                        "SYST:ERR?" => b"-113,\"Undefined header #15\"\n".to_vec(),
+                        "CURV?" => b"CURV #13a\nc,1#5\n".to_vec(),
+                        ":WAV:DATA?" => {
+                            let mut block = b"#210".to_vec();
+                            block.extend_from_slice(WAVEFORM);
+                            block.push(b'\n');
+                            block
+                        }
+                        _ => Vec::new(),
+                    };
+                    if stream.write_all(&response).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    address
+}
+
+#[test]
+fn tcp_query() {
+    let address = start_instrument();
+    let mut socket = open_socket(&address).unwrap();
+
+    write(&mut socket, "*RST").unwrap();
+    assert_eq!(query(&mut socket, "*IDN?").unwrap(), "SIM,TCP,0,1.0");
+}
+
+#[test]
+fn tcp_binary_block() {
+    let address = start_instrument();
+    let mut socket = open_socket(&address).unwrap();
+
+    let data = query_binary_data(&mut socket, ":WAV:DATA?").unwrap();
+    assert_eq!(data, WAVEFORM);
+
+    let mut streamed: Vec<u8> = Vec::new();
+    let size = query_binary_data_to(&mut socket, ":WAV:DATA?", &mut streamed).unwrap();
+    assert_eq!(size, WAVEFORM.len());
+    assert_eq!(streamed, WAVEFORM);
+
+    assert_eq!(query(&mut socket, "*IDN?").unwrap(), "SIM,TCP,0,1.0");
+}
+
+#[test]
+fn tcp_hash_in_text() {
+    let address = start_instrument();
+    let mut socket = open_socket(&address).unwrap();
+    socket.set_timeout(Duration::from_secs(1));
+
+    // '#' only starts a block where a data element starts, never inside a word or a string
+    assert_eq!(query(&mut socket, "LABEL?").unwrap(), "PROBE#15");
+    assert_eq!(
+        query(&mut socket, "SYST:ERR?").unwrap(),
+        "-113,\"Undefined header #15\""
+    );
+    assert_eq!(query(&mut socket, "CURV?").unwrap(), "CURV #13a\nc,1#5");
+}
+
+#[test]
+fn tcp_keeps_next_response() {
+    let address = start_instrument();
+    let mut socket = open_socket(&address).unwrap();
+
+    // both lines arrive together, the second one is the response to the next read
+    assert_eq!(query(&mut socket, "DOUBLE?").unwrap(), "1");
+
+    let mut second: Vec<u8> = Vec::new();
+    futures_lite::future::block_on(socket.read_message(&mut |payload: &[u8]| {
+        second.extend_from_slice(payload);
+        Ok(())
+    }))
+    .unwrap();
+    assert_eq!(second, b"2\n");
+}
+
+#[test]
+fn tcp_timeout_and_clear() {
+    let address = start_instrument();
+    let mut socket = open_socket(&address).unwrap();
+    socket.set_timeout(Duration::from_millis(200));
+
+    assert!(matches!(
+        query(&mut socket, "HANG?"),
+        Err(UsbtmcErrors::Timeout)
+    ));
+
+    futures_lite::future::block_on(socket.clear()).unwrap();
+    assert_eq!(query(&mut socket, "*IDN?").unwrap(), "SIM,TCP,0,1.0");
+}
+
+#[test]
+fn tcp_concurrent_sockets() {
+    use futures_lite::future::{block_on, zip};
+    use std::time::Instant;
+
+    let address = start_instrument();
+    let (mut hung, mut idle) = block_on(zip(
+        open_socket_async(&address),
+        open_socket_async(&address),
+    ));
+    let (hung, idle) = (hung.as_mut().unwrap(), idle.as_mut().unwrap());
+    hung.set_timeout(Duration::from_secs(1));
+
+    // a socket waiting for a response must not hold up the other one on the same executor
+    let start = Instant::now();
+    let (hang, idn) = block_on(zip(query_async(hung, "HANG?"), async {
+        (query_async(idle, "*IDN?").await, start.elapsed())
+    }));
+
+    assert!(matches!(hang, Err(UsbtmcErrors::Timeout)));
+    assert_eq!(idn.0.unwrap(), "SIM,TCP,0,1.0");
+    assert!(idn.1 < Duration::from_millis(500));
+}
+
+#[test]
+fn tcp_connect_refused() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    drop(listener);
+
+    assert!(open_socket_with_timeout(&address, Duration::from_millis(200)).is_err());
+}
@@ -1,6 +1,7 @@
 mod io;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use rscpi::transport::Transport;
 use rscpi::usbtmc::*;
 use rscpi::*;
 
@@ -12,6 +13,257 @@ fn info() {
     println!("{:#?}", device_all.collect::<Vec<_>>()); // Collect the iterator into a Vec and print it with pretty formatting
 }
 
+#[test]
+fn instruments() {
+    let instruments = list_instruments().unwrap();
+
+    for instrument in &instruments {
+        println!("{}", instrument.resource);
+        println!("{:#?}", instrument);
+    }
+}
+
+#[test]
+fn resource_string() {
+    let resource = parse_resource("USB0::0x2A8D::0x8D01::MY12345678::0::INSTR").unwrap();
+    assert_eq!(resource.vendor_id, 0x2A8D);
+    assert_eq!(resource.product_id, 0x8D01);
+    assert_eq!(resource.serial_number.as_deref(), Some("MY12345678"));
+    assert_eq!(resource.interface_number, Some(0));
+
+    let resource = parse_resource("usb::10893::36097::INSTR").unwrap();
+    assert_eq!(resource.board, 0);
+    assert_eq!(resource.vendor_id, 0x2A8D);
+    assert_eq!(resource.product_id, 0x8D01);
+    assert_eq!(resource.serial_number, None);
+    assert_eq!(resource.interface_number, None);
+
+    assert!(parse_resource("TCPIP0::192.168.1.10::INSTR").is_err());
+    assert!(parse_resource("USB0::0x2A8D::INSTR").is_err());
+}
+
+#[test]
+fn open_by_resource() {
+    let instruments = list_instruments().unwrap();
+    let instrument = instruments.first().expect("no USBTMC instrument connected");
+
+    let mut usbtmc = open_resource(&instrument.resource).unwrap();
+    println!("{}", query(&mut usbtmc, "*IDN?").unwrap());
+}
+
+#[test]
+fn open_by_serial() {
+    let instruments = list_instruments().unwrap();
+    let instrument = instruments.first().expect("no USBTMC instrument connected");
+    let serial = instrument.serial_number.clone().expect("no serial number");
+
+    let mut usbtmc = open_device_with(VID_PID, DeviceSelector::Serial(serial)).unwrap();
+    println!("{}", query(&mut usbtmc, "*IDN?").unwrap());
+
+    drop(usbtmc);
+
+    let path = instrument.port_path.clone().expect("no port path");
+    let mut usbtmc = open_device_with(VID_PID, DeviceSelector::PortPath(path)).unwrap();
+    println!("{}", query(&mut usbtmc, "*IDN?").unwrap());
+}
+
+// answers every query with the response it was built with
+struct LoopbackTransport {
+    response: Vec<u8>,
+    written: Vec<u8>,
+    timeout: Duration,
+    // like a USBTMC device with TermChar enabled, a response ends at the first '\n'
+    term_char_enabled: bool,
+}
+
+impl Transport for LoopbackTransport {
+    async fn write_message(&mut self, data: &[u8]) -> Result<(), UsbtmcErrors> {
+        self.written.extend_from_slice(data);
+        Ok(())
+    }
+
+    async fn read_message<F>(&mut self, on_payload: &mut F) -> Result<(), UsbtmcErrors>
+    where
+        F: FnMut(&[u8]) -> Result<(), UsbtmcErrors> + Send,
+    {
+        let end = match self.response.iter().position(|&byte| byte == b'\n') {
+            Some(position) if self.term_char_enabled => position + 1,
+            _ => self.response.len(),
+        };
+        for chunk in self.response[..end].chunks(3) {
+            on_payload(chunk)?;
+        }
+        Ok(())
+    }
+
+    async fn read_block_message<F>(&mut self, on_payload: &mut F) -> Result<(), UsbtmcErrors>
+    where
+        F: FnMut(&[u8]) -> Result<(), UsbtmcErrors> + Send,
+    {
+        for chunk in self.response.chunks(3) {
+            on_payload(chunk)?;
+        }
+        Ok(())
+    }
+
+    async fn clear(&mut self) -> Result<(), UsbtmcErrors> {
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn term_char(&self) -> u8 {
+        b'\n'
+    }
+}
+
+#[test]
+fn generic_transport() {
+    let mut transport = LoopbackTransport {
+        response: b"#15hello\n".to_vec(),
+        written: Vec::new(),
+        timeout: Duration::from_secs(1),
+        term_char_enabled: false,
+    };
+
+    assert_eq!(query(&mut transport, "*IDN?").unwrap(), "#15hello");
+    assert_eq!(
+        query_binary_data(&mut transport, ":WAV:DATA?").unwrap(),
+        b"hello"
+    );
+
+    let mut data: Vec<u8> = Vec::new();
+    let size = query_binary_data_to(&mut transport, ":WAV:DATA?", &mut data).unwrap();
+    assert_eq!(size, 5);
+    assert_eq!(data, b"hello");
+
+    write(&mut transport, "*RST").unwrap();
+    assert!(transport.written.ends_with(b"*RST\n"));
+}
+
+fn assert_send<T: Send>(future: T) -> T {
+    future
+}
+
+// measurement code written once against Transport, its futures can go to a multi-threaded executor
+async fn measure<T: Transport + Send>(
+    transport: &mut T,
+) -> Result<(String, Vec<u8>), UsbtmcErrors> {
+    let idn = assert_send(query_async(transport, "*IDN?")).await?;
+    assert_send(write_async(transport, "*RST")).await?;
+    let mut data: Vec<u8> = Vec::new();
+    assert_send(query_binary_data_chunks_async(
+        transport,
+        ":WAV:DATA?",
+        |chunk: &[u8]| {
+            data.extend_from_slice(chunk);
+            Ok(())
+        },
+    ))
+    .await?;
+    assert_send(transport.clear()).await?;
+
+    Ok((idn, data))
+}
+
+#[test]
+fn generic_future_is_send() {
+    let mut transport = LoopbackTransport {
+        response: b"#15hello\n".to_vec(),
+        written: Vec::new(),
+        timeout: Duration::from_secs(1),
+        term_char_enabled: false,
+    };
+
+    let (idn, data) = futures_lite::future::block_on(assert_send(measure(&mut transport))).unwrap();
+    assert_eq!(idn, "#15hello");
+    assert_eq!(data, b"hello");
+}
+
+#[test]
+fn block_ignores_term_char() {
+    let mut transport = LoopbackTransport {
+        response: b"#16\n1\n2\n3\n".to_vec(),
+        written: Vec::new(),
+        timeout: Duration::from_secs(1),
+        term_char_enabled: true,
+    };
+
+    // a text read ends at the first '\n', a block read runs to the end of the block
+    assert_eq!(query(&mut transport, "*IDN?").unwrap(), "#16");
+    assert_eq!(
+        query_binary_data(&mut transport, ":WAV:DATA?").unwrap(),
+        b"\n1\n2\n3"
+    );
+
+    let mut data: Vec<u8> = Vec::new();
+    query_binary_data_to(&mut transport, ":WAV:DATA?", &mut data).unwrap();
+    assert_eq!(data, b"\n1\n2\n3");
+}
+
+#[test]
+fn block_from_raw() {
+    assert_eq!(get_data_from_raw(b"#15hello").unwrap(), b"hello");
+    assert_eq!(get_data_from_raw(b"#0abc").unwrap(), b"abc");
+    assert_eq!(get_data_from_raw(b"#10").unwrap(), b"");
+
+    for raw in [
+        &b""[..],
+        b"#",
+        b"#A",
+        b"#3",
+        b"#25abc",
+        b"#2xyabc",
+        b"#15hello!",
+        b"15hello",
+    ] {
+        assert!(
+            matches!(get_data_from_raw(raw), Err(UsbtmcErrors::InvalidData)),
+            "{:?}",
+            raw
+        );
+    }
+}
+
+#[test]
+fn binary_block_framing() {
+    let transport = |response: &[u8]| LoopbackTransport {
+        response: response.to_vec(),
+        written: Vec::new(),
+        timeout: Duration::from_secs(1),
+        term_char_enabled: false,
+    };
+    let mut data: Vec<u8> = Vec::new();
+
+    let size = query_binary_data_to(&mut transport(b"#0abc\n"), ":WAV:DATA?", &mut data).unwrap();
+    assert_eq!(size, 3);
+    assert_eq!(data, b"abc");
+
+    // bytes after the block, a truncated block, no termination character
+    for response in [
+        &b"#15hello!\n"[..],
+        b"#15hel\n",
+        b"#15hello",
+        b"#0abc",
+        b"#10\n\n",
+    ] {
+        assert!(
+            matches!(
+                query_binary_data_to(&mut transport(response), ":WAV:DATA?", &mut data),
+                Err(UsbtmcErrors::InvalidData)
+            ),
+            "{:?}",
+            response
+        );
+    }
+}
+
 #[test]
 fn config() {
     let usbtmc = open_device(VID_PID).unwrap();
@@ -50,6 +302,28 @@ fn config() {
     println!("Endpoint out Address is: 0x{:x}", address_out);*/
 }
 
+#[test]
+fn idn_async() {
+    let mut usbtmc = open_device(VID_PID).unwrap();
+
+    let idn = futures_lite::future::block_on(query_async(&mut usbtmc, "*IDN?")).unwrap();
+    println!("{}", idn);
+}
+
+#[test]
+fn capabilities() {
+    let usbtmc = open_device(VID_PID).unwrap();
+
+    println!("Capabilities: {:#?}", usbtmc.capabilities);
+}
+
+#[test]
+fn protocol() {
+    let usbtmc = open_device(VID_PID).unwrap();
+
+    println!("Protocol: {:?}", usbtmc.protocol);
+}
+
 #[test]
 fn idn() {
     let mut usbtmc = open_device(VID_PID).unwrap();
@@ -60,6 +334,116 @@ fn idn() {
     println!("{}", idn);
 }
 
+#[test]
+fn clear_device() {
+    let mut usbtmc = open_device(VID_PID).unwrap();
+
+    // leave a response pending in the output queue
+    write_str(&mut usbtmc, "*IDN?\n").unwrap();
+
+    clear(&mut usbtmc).unwrap();
+
+    let idn = query(&mut usbtmc, "*IDN?").unwrap();
+    println!("{}", idn);
+}
+
+#[test]
+fn status_byte() {
+    let mut usbtmc = open_device(VID_PID).unwrap();
+
+    let stb = read_status_byte(&mut usbtmc).unwrap();
+    println!("Status byte: 0x{:02x}", stb);
+}
+
+#[test]
+fn srq_opc() {
+    let mut usbtmc = open_device(VID_PID).unwrap();
+
+    write(&mut usbtmc, "*CLS").unwrap();
+
+    // enable OPC in the Event Status Register and ESB in the Service Request Enable register
+    write(&mut usbtmc, "*ESE 1").unwrap();
+    write(&mut usbtmc, "*SRE 32").unwrap();
+
+    let start = Instant::now();
+
+    write(&mut usbtmc, ":DIGitize;*OPC").unwrap();
+
+    let stb = with_timeout(&mut usbtmc, Duration::from_secs(60), wait_for_srq).unwrap();
+    println!(
+        "SRQ after {:?}, status byte: 0x{:02x}",
+        start.elapsed(),
+        stb
+    );
+
+    let esr = query(&mut usbtmc, "*ESR?").unwrap();
+    println!("ESR: {}", esr);
+}
+
+#[test]
+fn lockout() {
+    let mut usbtmc = open_device(VID_PID).unwrap();
+
+    ren_control(&usbtmc, true).unwrap();
+    local_lockout(&usbtmc).unwrap();
+
+    let idn = query(&mut usbtmc, "*IDN?").unwrap();
+    println!("{}", idn);
+
+    go_to_local(&usbtmc).unwrap();
+    ren_control(&usbtmc, false).unwrap();
+}
+
+#[test]
+fn bus_trigger() {
+    let mut usbtmc = open_device(VID_PID).unwrap();
+
+    write(&mut usbtmc, ":TRIGger:SWEep NORMal").unwrap();
+    write(&mut usbtmc, ":SINGle").unwrap();
+
+    trigger(&mut usbtmc).unwrap();
+    check_scpi_error(&mut usbtmc);
+}
+
+#[test]
+fn reconnect_session() {
+    let mut usbtmc = open_device(VID_PID).unwrap();
+
+    set_event_handler(&mut usbtmc, |event| println!("Event: {:?}", event));
+    set_reconnect_policy(
+        &mut usbtmc,
+        Some(ReconnectPolicy {
+            init_commands: vec!["*CLS".to_string()],
+            ..ReconnectPolicy::default()
+        }),
+    );
+
+    // power-cycle the instrument while this runs
+    for _ in 0..30 {
+        match query(&mut usbtmc, "*IDN?") {
+            Ok(idn) => println!("{}", idn),
+            Err(error) => println!("{:?}", error),
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+
+    assert!(is_connected(&usbtmc));
+}
+
+#[test]
+fn timeout() {
+    let mut usbtmc = open_device(VID_PID).unwrap();
+
+    set_timeout(&mut usbtmc, Duration::from_millis(500));
+
+    // a command without '?' never produces a response
+    let result = send_command_raw_binary(&mut usbtmc, b"*CLS\n", true);
+    assert!(matches!(result, Err(UsbtmcErrors::Timeout)));
+
+    let idn = query(&mut usbtmc, "*IDN?").unwrap();
+    println!("{}", idn);
+}
+
 #[test]
 fn screenshot() {
     let mut usbtmc = open_device(VID_PID).unwrap();
@@ -76,7 +460,7 @@ fn check_scpi_error(usbtmc: &mut Usbtmc) {
 
     // check if the error string contains a comma
     if error.contains(',') {
-        let error_code = error.split(',').nth(0).unwrap().parse::<i32>().unwrap();
+        let error_code = error.split(',').next().unwrap().parse::<i32>().unwrap();
 
         if error_code != 0 {
             let error_message = error.split(',').nth(1).unwrap();
@@ -125,7 +509,10 @@ fn capture() {
 
     let start = Instant::now();
 
-    let data_raw = query_raw(&mut usbtmc, ":WAVeform:DATA?").unwrap();
+    let data_raw = with_timeout(&mut usbtmc, Duration::from_secs(60), |usbtmc| {
+        query_raw(usbtmc, ":WAVeform:DATA?")
+    })
+    .unwrap();
 
     println!("Capture duration: {:?}", start.elapsed());
 
@@ -134,6 +521,32 @@ fn capture() {
     io::write_to_file(data, "./output/data.bin").expect("failed to write to file");
 }
 
+#[test]
+fn capture_stream() {
+    let mut usbtmc = open_device(VID_PID).unwrap();
+
+    write(&mut usbtmc, "*CLS").unwrap();
+
+    write(&mut usbtmc, "ACQuire:POINts:ANALog 200e6").unwrap();
+    write(&mut usbtmc, ":CHANnel1:DISPlay ON").unwrap();
+    write(&mut usbtmc, ":DIGitize").unwrap();
+    write(&mut usbtmc, ":WAVeform:SOURce CHAN1").unwrap();
+    write(&mut usbtmc, ":WAVeform:FORMat BYTE").unwrap();
+    write(&mut usbtmc, ":WAVeform:STReaming ON").unwrap();
+    check_scpi_error(&mut usbtmc);
+
+    let mut file = std::fs::File::create("./output/data_stream.bin").unwrap();
+
+    let start = Instant::now();
+
+    let size = with_timeout(&mut usbtmc, Duration::from_secs(60), |usbtmc| {
+        query_binary_data_to(usbtmc, ":WAVeform:DATA?", &mut file)
+    })
+    .unwrap();
+
+    println!("Streamed {} bytes in {:?}", size, start.elapsed());
+}
+
 fn generate_ramp_f32(num_samples: usize) -> Vec<f32> {
     let mut samples = Vec::with_capacity(num_samples);
 
@@ -171,7 +584,7 @@ fn awg_screenshot() {
 
     let data = get_data_from_raw(&data_raw).unwrap();
 
-    io::write_to_file(&data, "./output/awg_screenshot.bmp").expect("failed to write to file");
+    io::write_to_file(data, "./output/awg_screenshot.bmp").expect("failed to write to file");
 }
 
 #[test]
@@ -185,7 +598,7 @@ fn awg_capture() {
 
     //write(&mut usbtmc, "*RST").unwrap();
 
-    let data = generate_ramp_f32(1024 * 4 as usize);
+    let data = generate_ramp_f32(1024 * 4);
 
     let bytes: Vec<u8> = data.iter().flat_map(|&f| f.to_be_bytes()).collect();
 
@@ -248,8 +661,8 @@ Data:\n",
     //concat the header and data to byte array
     let mut bytes = header.into_bytes();
 
-    for i in 0..data.len() {
-        let line = format!("{}\n", data[i]);
+    for sample in &data {
+        let line = format!("{}\n", sample);
         bytes.extend_from_slice(line.as_bytes());
     }
 
@@ -267,7 +680,7 @@ Data:\n",
 
     println!("Length = {}", result.len());
 
-    let _ = write_binary(&mut usbtmc, &result).unwrap();
+    write_binary(&mut usbtmc, &result).unwrap();
     check_scpi_error(&mut usbtmc);
 
     // write file to local storage
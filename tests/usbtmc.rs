@@ -0,0 +1,131 @@
+use rscpi::usbtmc::*;
+
+#[test]
+fn status_codes() {
+    assert_eq!(check_status(0x01).unwrap(), 0x01);
+    assert_eq!(check_status(0x02).unwrap(), 0x02);
+
+    assert!(matches!(
+        check_status(0x80),
+        Err(UsbtmcErrors::StatusFailed)
+    ));
+    assert!(matches!(
+        check_status(0x81),
+        Err(UsbtmcErrors::StatusTransferNotInProgress)
+    ));
+    assert!(matches!(
+        check_status(0x82),
+        Err(UsbtmcErrors::StatusSplitNotInProgress)
+    ));
+    assert!(matches!(
+        check_status(0x83),
+        Err(UsbtmcErrors::StatusSplitInProgress)
+    ));
+    assert!(matches!(
+        check_status(0x20),
+        Err(UsbtmcErrors::StatusInterruptInBusy)
+    ));
+    assert!(matches!(
+        check_status(0x55),
+        Err(UsbtmcErrors::StatusUnknown(0x55))
+    ));
+}
+
+#[test]
+fn status_polling() {
+    // CHECK_ABORT_BULK_IN_STATUS, 8 bytes with the number of bytes sent at the end
+    let done = [0x01, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00];
+    assert_eq!(poll_check_status(&done).unwrap(), StatusPoll::Done);
+
+    // still pending, with and without data left in the Bulk-IN FIFO
+    assert_eq!(
+        poll_check_status(&[0x02, 0x01]).unwrap(),
+        StatusPoll::DrainBulkIn
+    );
+    assert_eq!(poll_check_status(&[0x02, 0x00]).unwrap(), StatusPoll::Wait);
+
+    // SUCCESS ends the polling even if D0 is set
+    assert_eq!(poll_check_status(&[0x01, 0x01]).unwrap(), StatusPoll::Done);
+
+    assert!(matches!(
+        poll_check_status(&[0x80, 0x00]),
+        Err(UsbtmcErrors::StatusFailed)
+    ));
+    assert!(matches!(
+        poll_check_status(&[0x02]),
+        Err(UsbtmcErrors::InvalidData)
+    ));
+}
+
+#[test]
+fn vendor_specific_headers() {
+    // MsgID, bTag, inverted bTag, reserved, TransferSize little endian, reserved
+    assert_eq!(
+        pack_vendor_specific_out_header(0x0001_0203, 0x2A),
+        [126, 0x2A, 0xD5, 0x00, 0x03, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]
+    );
+    assert_eq!(
+        pack_vendor_specific_in_header(512, 0xFF),
+        [127, 0xFF, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+    );
+}
+
+// DEV_DEP_MSG_IN response header followed by `payload`
+fn dev_dep_msg_in(btag: u8, transfer_size: u32, attributes: u8, payload: &[u8]) -> Vec<u8> {
+    let mut transfer = vec![2, btag, !btag, 0];
+    transfer.extend_from_slice(&transfer_size.to_le_bytes());
+    transfer.extend_from_slice(&[attributes, 0, 0, 0]);
+    transfer.extend_from_slice(payload);
+    transfer
+}
+
+#[test]
+fn bulk_in_header() {
+    // three alignment bytes after the payload
+    let transfer = dev_dep_msg_in(7, 5, 0x01, b"hello\0\0\0");
+    let (header, payload) = parse_bulk_in_header(&transfer, 2, 7).unwrap();
+    assert_eq!(
+        header,
+        BulkInHeader {
+            transfer_size: 5,
+            eom: true,
+            term_char: false,
+        }
+    );
+    assert_eq!(payload, b"hello");
+
+    // the rest of the payload comes in later transfers, the transfer ended on TermChar
+    let transfer = dev_dep_msg_in(7, 100, 0x02, b"hel");
+    let (header, payload) = parse_bulk_in_header(&transfer, 2, 7).unwrap();
+    assert!(!header.eom);
+    assert!(header.term_char);
+    assert_eq!(header.transfer_size, 100);
+    assert_eq!(payload, b"hel");
+}
+
+#[test]
+fn bulk_in_header_mismatch() {
+    let transfer = dev_dep_msg_in(7, 5, 0x01, b"hello");
+
+    assert!(matches!(
+        parse_bulk_in_header(&transfer, 127, 7),
+        Err(UsbtmcErrors::MsgIdMismatch(2))
+    ));
+    // stale data from the previous request
+    assert!(matches!(
+        parse_bulk_in_header(&transfer, 2, 8),
+        Err(UsbtmcErrors::BtagMismatch(7))
+    ));
+
+    let mut transfer = transfer;
+    transfer[2] = 0;
+    assert!(matches!(
+        parse_bulk_in_header(&transfer, 2, 7),
+        Err(UsbtmcErrors::BtagInverseMismatch)
+    ));
+
+    assert!(matches!(
+        parse_bulk_in_header(&transfer[..8], 2, 7),
+        Err(UsbtmcErrors::InvalidData)
+    ));
+}
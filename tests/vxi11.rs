@@ -0,0 +1,463 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rscpi::usbtmc::*;
+use rscpi::vxi11::*;
+use rscpi::*;
+
+// small enough that longer commands are split into several device_write calls
+const MAX_RECV_SIZE: u32 = 16;
+// device_read hands out at most this many bytes so responses take several calls
+const READ_CHUNK: usize = 7;
+
+#[derive(Default)]
+struct Instrument {
+    input: Vec<u8>,
+    output: Vec<u8>,
+    next_link: u32,
+    lock_owner: Option<u32>,
+    triggers: u32,
+    clears: u32,
+    aborts: u32,
+    // calls on the core channel get no reply for a while
+    stalled: bool,
+}
+
+struct StandIn {
+    portmapper: String,
+    instrument: Arc<Mutex<Instrument>>,
+}
+
+fn read_u32(data: &mut &[u8]) -> u32 {
+    let (value, rest) = data.split_at(4);
+    *data = rest;
+    u32::from_be_bytes([value[0], value[1], value[2], value[3]])
+}
+
+fn read_opaque(data: &mut &[u8]) -> Vec<u8> {
+    let size = read_u32(data) as usize;
+    let (value, rest) = data.split_at(size + (4 - size % 4) % 4);
+    *data = rest;
+    value[..size].to_vec()
+}
+
+fn push_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_opaque(data: &mut Vec<u8>, value: &[u8]) {
+    push_u32(data, value.len() as u32);
+    data.extend_from_slice(value);
+    data.resize(data.len() + (4 - value.len() % 4) % 4, 0);
+}
+
+/*
+* Serves RPC calls on every connection to `listener`, `handle(procedure, params)` returns the
+* results of the call
+*/
+fn serve<F>(listener: TcpListener, handle: F)
+where
+    F: Fn(u32, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+{
+    let handle = Arc::new(handle);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream: TcpStream = stream.unwrap();
+            let handle = handle.clone();
+
+            thread::spawn(move || loop {
+                let mut header = [0u8; 4];
+                if stream.read_exact(&mut header).is_err() {
+                    break;
+                }
+                let size = (u32::from_be_bytes(header) & 0x7FFF_FFFF) as usize;
+                let mut call = vec![0u8; size];
+                stream.read_exact(&mut call).unwrap();
+
+                let mut params: &[u8] = &call;
+                let xid = read_u32(&mut params);
+                let _msg_type = read_u32(&mut params);
+                let _rpc_version = read_u32(&mut params);
+                let _program = read_u32(&mut params);
+                let _version = read_u32(&mut params);
+                let procedure = read_u32(&mut params);
+                let _credential_flavor = read_u32(&mut params);
+                let _credential = read_opaque(&mut params);
+                let _verifier_flavor = read_u32(&mut params);
+                let _verifier = read_opaque(&mut params);
+
+                let results = handle(procedure, params);
+
+                let mut reply = Vec::new();
+                push_u32(&mut reply, xid);
+                push_u32(&mut reply, 1);
+                push_u32(&mut reply, 0);
+                push_u32(&mut reply, 0);
+                push_opaque(&mut reply, &[]);
+                push_u32(&mut reply, 0);
+                reply.extend_from_slice(&results);
+
+                let mut record = (0x8000_0000 | reply.len() as u32).to_be_bytes().to_vec();
+                record.extend_from_slice(&reply);
+                if stream.write_all(&record).is_err() {
+                    break;
+                }
+            });
+        }
+    });
+}
+
+fn respond(command: &[u8]) -> Vec<u8> {
+    let command = String::from_utf8_lossy(command).trim().to_string();
+
+    if command == "*IDN?" {
+        b"SIM,VXI11,0,1.0\n".to_vec()
+    } else if command == ":WAV:DATA?" {
+        let mut block = b"#220".to_vec();
+        block.extend((0..20).map(|i| i as u8));
+        block.push(b'\n');
+        block
+    } else if let Some(text) = command.strip_prefix("ECHO? ") {
+        format!("{}\n", text).into_bytes()
+    } else {
+        Vec::new()
+    }
+}
+
+fn core_procedure(
+    instrument: &Mutex<Instrument>,
+    abort_port: u16,
+    procedure: u32,
+    mut params: &[u8],
+) -> Vec<u8> {
+    let mut instrument = instrument.lock().unwrap();
+    let mut results = Vec::new();
+
+    match procedure {
+        // create_link
+        10 => {
+            let _client_id = read_u32(&mut params);
+            let _lock_device = read_u32(&mut params);
+            let _lock_timeout = read_u32(&mut params);
+            let device = read_opaque(&mut params);
+
+            if device == b"inst0" {
+                instrument.next_link += 1;
+                push_u32(&mut results, 0);
+                push_u32(&mut results, instrument.next_link);
+            } else {
+                push_u32(&mut results, 3);
+                push_u32(&mut results, 0);
+            }
+            push_u32(&mut results, abort_port as u32);
+            push_u32(&mut results, MAX_RECV_SIZE);
+        }
+        // device_write
+        11 => {
+            let link = read_u32(&mut params);
+            let _io_timeout = read_u32(&mut params);
+            let _lock_timeout = read_u32(&mut params);
+            let flags = read_u32(&mut params);
+            let data = read_opaque(&mut params);
+
+            if instrument.lock_owner.is_some_and(|owner| owner != link) {
+                push_u32(&mut results, 11);
+                push_u32(&mut results, 0);
+            } else {
+                assert!(data.len() <= MAX_RECV_SIZE as usize);
+                instrument.input.extend_from_slice(&data);
+                if flags & 0x08 != 0 {
+                    let input = std::mem::take(&mut instrument.input);
+                    instrument.output = respond(&input);
+                }
+                push_u32(&mut results, 0);
+                push_u32(&mut results, data.len() as u32);
+            }
+        }
+        // device_read
+        12 => {
+            let _link = read_u32(&mut params);
+            let request_size = read_u32(&mut params) as usize;
+            let _io_timeout = read_u32(&mut params);
+            let _lock_timeout = read_u32(&mut params);
+            let flags = read_u32(&mut params);
+            let term_char = read_u32(&mut params) as u8;
+
+            if instrument.output.is_empty() {
+                // I/O timeout
+                push_u32(&mut results, 15);
+                push_u32(&mut results, 0);
+                push_opaque(&mut results, &[]);
+            } else {
+                let mut size = instrument.output.len().min(request_size).min(READ_CHUNK);
+                // TERMCHRSET, the read ends after the termination character
+                let term_char_end = flags & 0x80 != 0
+                    && match instrument.output[..size]
+                        .iter()
+                        .position(|&b| b == term_char)
+                    {
+                        Some(position) => {
+                            size = position + 1;
+                            true
+                        }
+                        None => false,
+                    };
+                let data: Vec<u8> = instrument.output.drain(..size).collect();
+                let reason = if instrument.output.is_empty() {
+                    0x04
+                } else if term_char_end {
+                    0x02
+                } else {
+                    0x01
+                };
+                push_u32(&mut results, 0);
+                push_u32(&mut results, reason);
+                push_opaque(&mut results, &data);
+            }
+        }
+        // device_readstb
+        13 => {
+            push_u32(&mut results, 0);
+            push_u32(&mut results, 0x42);
+        }
+        // device_trigger
+        14 => {
+            instrument.triggers += 1;
+            push_u32(&mut results, 0);
+        }
+        // device_clear
+        15 => {
+            instrument.clears += 1;
+            instrument.output.clear();
+            push_u32(&mut results, 0);
+        }
+        // device_lock
+        18 => {
+            let link = read_u32(&mut params);
+            if instrument.lock_owner.is_some_and(|owner| owner != link) {
+                push_u32(&mut results, 11);
+            } else {
+                instrument.lock_owner = Some(link);
+                push_u32(&mut results, 0);
+            }
+        }
+        // device_unlock
+        19 => {
+            let link = read_u32(&mut params);
+            if instrument.lock_owner == Some(link) {
+                instrument.lock_owner = None;
+                push_u32(&mut results, 0);
+            } else {
+                push_u32(&mut results, 12);
+            }
+        }
+        // device_remote, device_local, destroy_link
+        _ => push_u32(&mut results, 0),
+    }
+
+    results
+}
+
+/*
+* With the waitlock flag set, device_lock waits up to lock_timeout for another link to release the
+* lock
+*/
+fn wait_for_lock(instrument: &Mutex<Instrument>, mut params: &[u8]) {
+    let link = read_u32(&mut params);
+    let flags = read_u32(&mut params);
+    let lock_timeout = Duration::from_millis(read_u32(&mut params) as u64);
+    let start = Instant::now();
+
+    while flags & 0x01 != 0 && start.elapsed() < lock_timeout {
+        if instrument
+            .lock()
+            .unwrap()
+            .lock_owner
+            .is_none_or(|owner| owner == link)
+        {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn start_instrument() -> StandIn {
+    let instrument = Arc::new(Mutex::new(Instrument::default()));
+
+    let core = TcpListener::bind("127.0.0.1:0").unwrap();
+    let core_port = core.local_addr().unwrap().port();
+    let abort = TcpListener::bind("127.0.0.1:0").unwrap();
+    let abort_port = abort.local_addr().unwrap().port();
+    let portmapper = TcpListener::bind("127.0.0.1:0").unwrap();
+    let portmapper_address = portmapper.local_addr().unwrap().to_string();
+
+    // GETPORT always answers with the core channel
+    serve(portmapper, move |_, _| {
+        (core_port as u32).to_be_bytes().to_vec()
+    });
+
+    let core_instrument = instrument.clone();
+    serve(core, move |procedure, params| {
+        if core_instrument.lock().unwrap().stalled {
+            thread::sleep(Duration::from_secs(3));
+        }
+        if procedure == 18 {
+            wait_for_lock(&core_instrument, params);
+        }
+        core_procedure(&core_instrument, abort_port, procedure, params)
+    });
+
+    let abort_instrument = instrument.clone();
+    serve(abort, move |_, _| {
+        abort_instrument.lock().unwrap().aborts += 1;
+        vec![0, 0, 0, 0]
+    });
+
+    StandIn {
+        portmapper: portmapper_address,
+        instrument,
+    }
+}
+
+#[test]
+fn vxi11_query() {
+    let stand_in = start_instrument();
+    let mut vxi11 = open_vxi11(&stand_in.portmapper, "inst0").unwrap();
+
+    write(&mut vxi11, "*RST").unwrap();
+    assert_eq!(query(&mut vxi11, "*IDN?").unwrap(), "SIM,VXI11,0,1.0");
+
+    let text = "a command longer than the max receive size";
+    assert_eq!(query(&mut vxi11, &format!("ECHO? {}", text)).unwrap(), text);
+
+    let data = query_binary_data(&mut vxi11, ":WAV:DATA?").unwrap();
+    assert_eq!(data, (0..20).collect::<Vec<u8>>());
+
+    close_vxi11(vxi11).unwrap();
+}
+
+#[test]
+fn vxi11_term_char() {
+    let stand_in = start_instrument();
+    let mut vxi11 = open_vxi11(&stand_in.portmapper, "inst0").unwrap();
+    set_vxi11_term_char(&mut vxi11, b'\n', true);
+
+    assert_eq!(query(&mut vxi11, "*IDN?").unwrap(), "SIM,VXI11,0,1.0");
+
+    // the block holds a '\n', a block read never lets the device stop on it
+    let data = query_binary_data(&mut vxi11, ":WAV:DATA?").unwrap();
+    assert_eq!(data, (0..20).collect::<Vec<u8>>());
+}
+
+#[test]
+fn vxi11_device_operations() {
+    let stand_in = start_instrument();
+    let mut vxi11 = open_vxi11(&stand_in.portmapper, "inst0").unwrap();
+
+    assert_eq!(vxi11_read_status_byte(&mut vxi11).unwrap(), 0x42);
+    vxi11_trigger(&mut vxi11).unwrap();
+    vxi11_remote(&mut vxi11).unwrap();
+    vxi11_go_to_local(&mut vxi11).unwrap();
+
+    write(&mut vxi11, "*IDN?").unwrap();
+    vxi11_clear(&mut vxi11).unwrap();
+
+    let handle = vxi11_abort_handle(&vxi11);
+    thread::spawn(move || vxi11_abort(&handle))
+        .join()
+        .unwrap()
+        .unwrap();
+
+    let instrument = stand_in.instrument.lock().unwrap();
+    assert_eq!(instrument.triggers, 1);
+    assert_eq!(instrument.clears, 1);
+    assert_eq!(instrument.aborts, 1);
+}
+
+#[test]
+fn vxi11_locking() {
+    let stand_in = start_instrument();
+    let mut first = open_vxi11(&stand_in.portmapper, "inst0").unwrap();
+    let mut second = open_vxi11(&stand_in.portmapper, "inst0").unwrap();
+
+    vxi11_lock(&mut first, Duration::ZERO).unwrap();
+    assert!(matches!(
+        write(&mut second, "*RST"),
+        Err(UsbtmcErrors::Vxi11Error(11))
+    ));
+    assert!(matches!(
+        vxi11_lock(&mut second, Duration::ZERO),
+        Err(UsbtmcErrors::Vxi11Error(11))
+    ));
+
+    vxi11_unlock(&mut first).unwrap();
+    assert_eq!(query(&mut second, "*IDN?").unwrap(), "SIM,VXI11,0,1.0");
+}
+
+#[test]
+fn vxi11_errors() {
+    let stand_in = start_instrument();
+
+    assert!(matches!(
+        open_vxi11(&stand_in.portmapper, "inst9"),
+        Err(UsbtmcErrors::Vxi11Error(3))
+    ));
+
+    // the stand-in reports an I/O timeout when there is nothing to read
+    let mut vxi11 = open_vxi11(&stand_in.portmapper, "inst0").unwrap();
+    assert!(matches!(
+        query(&mut vxi11, "NOTHING?"),
+        Err(UsbtmcErrors::Timeout)
+    ));
+}
+
+#[test]
+fn vxi11_rpc_timeout() {
+    use rscpi::transport::Transport;
+
+    let stand_in = start_instrument();
+    let mut vxi11 = open_vxi11(&stand_in.portmapper, "inst0").unwrap();
+    vxi11.set_timeout(Duration::from_millis(200));
+    stand_in.instrument.lock().unwrap().stalled = true;
+
+    // the reply is given up on a second after io_timeout, long before the stand-in answers
+    let start = Instant::now();
+    assert!(matches!(
+        vxi11_read_status_byte(&mut vxi11),
+        Err(UsbtmcErrors::Timeout)
+    ));
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    // the late reply is left behind with the old connection, the next call gets a new link
+    stand_in.instrument.lock().unwrap().stalled = false;
+    assert_eq!(query(&mut vxi11, "*IDN?").unwrap(), "SIM,VXI11,0,1.0");
+    assert_eq!(stand_in.instrument.lock().unwrap().next_link, 2);
+}
+
+#[test]
+fn vxi11_lock_wait() {
+    use rscpi::transport::Transport;
+
+    let stand_in = start_instrument();
+    let mut first = open_vxi11(&stand_in.portmapper, "inst0").unwrap();
+    let mut second = open_vxi11(&stand_in.portmapper, "inst0").unwrap();
+    second.set_timeout(Duration::from_millis(200));
+
+    vxi11_lock(&mut first, Duration::ZERO).unwrap();
+    let release = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(1500));
+        vxi11_unlock(&mut first).unwrap();
+    });
+
+    // the wait is much longer than io_timeout and the RPC deadline allows for it
+    let start = Instant::now();
+    vxi11_lock(&mut second, Duration::from_secs(3)).unwrap();
+    assert!(start.elapsed() > Duration::from_millis(1200));
+    release.join().unwrap();
+
+    vxi11_unlock(&mut second).unwrap();
+}